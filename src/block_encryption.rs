@@ -1,25 +1,146 @@
-use aes::cipher::{KeyIvInit, StreamCipher};
-use aes::Aes128;
-use ctr::Ctr128BE;
-use image::{ImageBuffer, Rgba};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes128Gcm, Nonce};
+use crate::key_store::{BlockKey, KeyStore, KeyStoreError, KEY_LEN};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use image::{ColorType, DynamicImage, GenericImageView, ImageBuffer};
+use rand::rngs::OsRng;
+use rand::RngCore;
 use sha2::{Digest, Sha256};
 use std::fs::File;
 use std::io::Write;
 use std::path::Path;
+use thiserror::Error;
 
-type Aes128Ctr = Ctr128BE<Aes128>;
+// Size of the nonce stored in front of every encrypted block
+pub const NONCE_LEN: usize = 12;
 
-// Simple XOR cipher for encryption and decryption
-fn xor_cipher(data: &mut [u8], key: u8) {
-    for byte in data.iter_mut() {
-        *byte ^= key;
+// Size of the authentication tag appended by AES-GCM
+pub const TAG_LEN: usize = 16;
+
+// Size of the HMAC-SHA256 key of a block
+pub const MAC_KEY_LEN: usize = 32;
+
+#[derive(Debug, Error)]
+pub enum BlockEncryptionError {
+    #[error("encrypted block is too short: {0} bytes")]
+    Truncated(usize),
+    #[error("encrypted block has an invalid key id")]
+    InvalidKeyId,
+    #[error(transparent)]
+    KeyStore(#[from] KeyStoreError),
+    #[error("authentication tag of block {0} does not verify")]
+    AuthenticationFailed(u32),
+    #[error("decrypted block has {actual} bytes, expected {expected}")]
+    SizeMismatch { expected: usize, actual: usize },
+    #[error("unsupported block color type: {0}")]
+    UnsupportedColor(String),
+}
+
+// Random nonce of a single encryption. Blocks of the registered and the suspect image share
+// their block keys, so a nonce derived from the block index would be reused whenever a block
+// is encrypted twice, leaking the XOR of both plaintexts and the GHASH key.
+fn random_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+// Derive the key of a single block from the image data key with HKDF-SHA256.
+// The image id is the salt and the block index the info, so a block key can be handed
+// to a third party without revealing any other block of the image. Every encryption of the
+// block uses this key, e.g. for the registered and the suspect image, under a random nonce.
+pub fn derive_block_key(data_key: &BlockKey, image_id: &str, block_index: u32) -> [u8; KEY_LEN] {
+    let hkdf = Hkdf::<Sha256>::new(Some(image_id.as_bytes()), data_key.bytes());
+
+    let mut info = b"image-auth block key".to_vec();
+    info.extend_from_slice(&block_index.to_be_bytes());
+
+    let mut block_key = [0u8; KEY_LEN];
+    hkdf.expand(&info, &mut block_key).expect("Block key length is valid for HKDF");
+    block_key
+}
+
+// Derive the key authenticating the features of a single block, like its encryption key
+pub fn derive_mac_key(data_key: &BlockKey, image_id: &str, block_index: u32) -> [u8; MAC_KEY_LEN] {
+    let hkdf = Hkdf::<Sha256>::new(Some(image_id.as_bytes()), data_key.bytes());
+
+    let mut info = b"image-auth block mac".to_vec();
+    info.extend_from_slice(&block_index.to_be_bytes());
+
+    let mut mac_key = [0u8; MAC_KEY_LEN];
+    hkdf.expand(&info, &mut mac_key).expect("MAC key length is valid for HKDF");
+    mac_key
+}
+
+// HMAC-SHA256 of the raw samples of a block and of its position in the registered image, hex
+// encoded. Ciphertexts differ on every encryption, so unchanged blocks of the suspect image are
+// recognised by their MACs, which only the holders of the data key can compute.
+pub fn block_mac(data: &[u8], block_dimensions: (u32, u32), key: &BlockKey, image_id: &str, block_index: u32) -> String {
+    block_mac_with_key(data, block_dimensions, &derive_mac_key(key, image_id, block_index), image_id, block_index)
+}
+
+// MAC of a block with its own MAC key, e.g. one received in a disclosure bundle
+pub fn block_mac_with_key(data: &[u8], block_dimensions: (u32, u32), mac_key: &[u8; MAC_KEY_LEN], image_id: &str, block_index: u32) -> String {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(mac_key).expect("HMAC accepts keys of any length");
+    mac.update(&associated_data(image_id, block_index, block_dimensions));
+    mac.update(data);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// Associated data binding a ciphertext to its position in the registered image:
+// block index | block width | block height | image id length | image id. Both dimensions and the
+// length of the image id are written, so that no two positions share their associated data.
+fn associated_data(image_id: &str, block_index: u32, (block_width, block_height): (u32, u32)) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(16 + image_id.len());
+    aad.extend_from_slice(&block_index.to_be_bytes());
+    aad.extend_from_slice(&block_width.to_be_bytes());
+    aad.extend_from_slice(&block_height.to_be_bytes());
+    aad.extend_from_slice(&(image_id.len() as u32).to_be_bytes());
+    aad.extend_from_slice(image_id.as_bytes());
+    aad
+}
+
+// Split an encrypted block into its key id and the remaining nonce, ciphertext and tag
+fn split_key_id(data: &[u8]) -> Result<(&str, &[u8]), BlockEncryptionError> {
+    let (&id_len, rest) = data.split_first().ok_or(BlockEncryptionError::Truncated(data.len()))?;
+    if rest.len() < id_len as usize {
+        return Err(BlockEncryptionError::Truncated(data.len()));
+    }
+
+    let (key_id, rest) = rest.split_at(id_len as usize);
+    let key_id = std::str::from_utf8(key_id).map_err(|_| BlockEncryptionError::InvalidKeyId)?;
+    Ok((key_id, rest))
+}
+
+// Raw samples of a block. 16-bit samples are stored big-endian so that the plaintext,
+// and therefore the MAC, does not depend on the platform.
+pub fn block_samples(block: &DynamicImage) -> Vec<u8> {
+    match block.as_flat_samples_u16() {
+        Some(samples) => samples.samples.iter().flat_map(|sample| sample.to_be_bytes()).collect(),
+        None => block.to_bytes(),
     }
 }
 
-pub fn encrypt_block(block: &ImageBuffer<Rgba<u8>, Vec<u8>>, key: u8) -> Vec<u8> {
-    let mut block_data = block.as_raw().clone();
-    xor_cipher(&mut block_data, key);
-    block_data
+// Encrypt the raw samples of a block with AES-128-GCM under the block key derived from `key`.
+// Layout: key id length (1 byte) | key id | nonce | ciphertext and tag
+pub fn encrypt_block_data(data: &[u8], block_dimensions: (u32, u32), key: &BlockKey, image_id: &str, block_index: u32) -> Vec<u8> {
+    let nonce = random_nonce();
+    let aad = associated_data(image_id, block_index, block_dimensions);
+    let block_key = derive_block_key(key, image_id, block_index);
+
+    let cipher = Aes128Gcm::new((&block_key).into());
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: data, aad: &aad })
+        .expect("Failed to encrypt block");
+
+    let key_id = key.id().as_bytes();
+    let mut encrypted = Vec::with_capacity(1 + key_id.len() + NONCE_LEN + ciphertext.len());
+    encrypted.push(key_id.len() as u8);
+    encrypted.extend_from_slice(key_id);
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend_from_slice(&ciphertext);
+    encrypted
 }
 
 pub fn save_to_file(data: &[u8], path: &Path) {
@@ -27,44 +148,213 @@ pub fn save_to_file(data: &[u8], path: &Path) {
     file.write_all(data).expect("Failed to write data to file");
 }
 
-pub fn encrypt_and_save_blocks(blocks: &[ImageBuffer<Rgba<u8>, Vec<u8>>], prefix: &str) {
-    let key = 0xAA; // Example key for XOR encryption
-    for (i, block) in blocks.iter().enumerate() {
-        let encrypted_block = encrypt_block(block, key);
-        let file_name = format!("{}_block_{}.enc", prefix, i + 1);
-        save_to_file(&encrypted_block, Path::new(&file_name));
-        
-        // Calculate and print hash for debugging
-        let mut hasher = Sha256::new();
-        hasher.update(&encrypted_block);
-        let hash = hasher.finalize();
-        println!("Block {}: Encrypted hash: {}", i + 1, hex::encode(hash));
-
-        println!("Saved {}", file_name);
-    }
-}
-
-pub fn decrypt_block(data: &[u8], block_size: u32) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
-    let key = 0xAA; // Example key for XOR decryption
-
-    // Perform XOR decryption
-    let mut decrypted_data = data.to_vec();
-    xor_cipher(&mut decrypted_data, key);
-
-    // Reconstruct the image buffer from decrypted data
-    let mut block = ImageBuffer::new(block_size, block_size);
-    for (i, pixel) in decrypted_data.chunks(4).enumerate() {
-        let x = (i as u32) % block_size;
-        let y = (i as u32) / block_size;
-        
-        // Ensure bounds checking for pixel data
-        if let [r, g, b, a] = pixel {
-            block.put_pixel(x, y, Rgba([*r, *g, *b, *a]));
-        } else {
-            // Handle unexpected chunk size
-            eprintln!("Unexpected chunk size during pixel reconstruction.");
-        }
+// Encrypt a block and compute the MAC of its samples, which is its leaf
+pub fn seal_block(block: &DynamicImage, key: &BlockKey, image_id: &str, block_index: u32) -> (Vec<u8>, String) {
+    let samples = block_samples(block);
+    let encrypted_block = encrypt_block_data(&samples, block.dimensions(), key, image_id, block_index);
+    (encrypted_block, block_mac(&samples, block.dimensions(), key, image_id, block_index))
+}
+
+// Save an encrypted block to its own file with the given prefix
+pub fn save_encrypted_block(encrypted_block: &[u8], block_index: u32, prefix: &str) {
+    let file_name = format!("{}_block_{}.enc", prefix, block_index + 1);
+    save_to_file(encrypted_block, Path::new(&file_name));
+
+    // Calculate and print hash for debugging
+    let mut hasher = Sha256::new();
+    hasher.update(encrypted_block);
+    let hash = hasher.finalize();
+    println!("Block {}: Encrypted hash: {}", block_index + 1, hex::encode(hash));
+
+    println!("Saved {}", file_name);
+}
+
+// Decrypt a block with the key named in its header and verify that it belongs
+// to `block_index` of `image_id`
+pub fn decrypt_block(data: &[u8], key_store: &KeyStore, image_id: &str, block_index: u32, block_dimensions: (u32, u32), color: ColorType) -> Result<DynamicImage, BlockEncryptionError> {
+    let decrypted_data = decrypt_block_data(data, key_store, image_id, block_index, block_dimensions)?;
+    decode_block(decrypted_data, block_dimensions, color)
+}
+
+// Decrypt the raw samples of a block with the key named in its header
+pub fn decrypt_block_data(data: &[u8], key_store: &KeyStore, image_id: &str, block_index: u32, block_dimensions: (u32, u32)) -> Result<Vec<u8>, BlockEncryptionError> {
+    let (key_id, _) = split_key_id(data)?;
+    let key = key_store.get(key_id)?;
+    let block_key = derive_block_key(key, image_id, block_index);
+
+    decrypt_block_data_with_key(data, &block_key, image_id, block_index, block_dimensions)
+}
+
+// Decrypt the raw samples of a block with its own block key, e.g. one received in a disclosure bundle
+pub fn decrypt_block_data_with_key(data: &[u8], block_key: &[u8; KEY_LEN], image_id: &str, block_index: u32, block_dimensions: (u32, u32)) -> Result<Vec<u8>, BlockEncryptionError> {
+    let (_, payload) = split_key_id(data)?;
+    if payload.len() < NONCE_LEN + TAG_LEN {
+        return Err(BlockEncryptionError::Truncated(data.len()));
     }
-    block
+
+    // Split the stored nonce from the ciphertext
+    let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+    let aad = associated_data(image_id, block_index, block_dimensions);
+
+    // Perform AES-GCM decryption, rejecting corrupted or swapped blocks
+    let cipher = Aes128Gcm::new(block_key.into());
+    cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+        .map_err(|_| BlockEncryptionError::AuthenticationFailed(block_index))
 }
 
+// Reconstruct the image buffer of a block from its decrypted samples
+pub fn decode_block(data: Vec<u8>, (block_width, block_height): (u32, u32), color: ColorType) -> Result<DynamicImage, BlockEncryptionError> {
+    let expected = (block_width * block_height) as usize * color.bytes_per_pixel() as usize;
+    if data.len() != expected {
+        return Err(BlockEncryptionError::SizeMismatch { expected, actual: data.len() });
+    }
+
+    let block = match color {
+        ColorType::L8 => ImageBuffer::from_raw(block_width, block_height, data).map(DynamicImage::ImageLuma8),
+        ColorType::La8 => ImageBuffer::from_raw(block_width, block_height, data).map(DynamicImage::ImageLumaA8),
+        ColorType::Rgba8 => ImageBuffer::from_raw(block_width, block_height, data).map(DynamicImage::ImageRgba8),
+        ColorType::L16 => ImageBuffer::from_raw(block_width, block_height, be_samples(&data)).map(DynamicImage::ImageLuma16),
+        ColorType::La16 => ImageBuffer::from_raw(block_width, block_height, be_samples(&data)).map(DynamicImage::ImageLumaA16),
+        ColorType::Rgba16 => ImageBuffer::from_raw(block_width, block_height, be_samples(&data)).map(DynamicImage::ImageRgba16),
+        other => return Err(BlockEncryptionError::UnsupportedColor(format!("{:?}", other))),
+    };
+    Ok(block.expect("Block size was checked above"))
+}
+
+// 16-bit samples stored big-endian by `block_samples`
+fn be_samples(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2).map(|sample| u16::from_be_bytes([sample[0], sample[1]])).collect()
+}
+
+// Name of a block color type, as recorded outside the binary
+pub fn color_type_name(color: ColorType) -> String {
+    format!("{:?}", color)
+}
+
+pub fn parse_color_type(name: &str) -> Result<ColorType, BlockEncryptionError> {
+    match name {
+        "L8" => Ok(ColorType::L8),
+        "La8" => Ok(ColorType::La8),
+        "Rgba8" => Ok(ColorType::Rgba8),
+        "L16" => Ok(ColorType::L16),
+        "La16" => Ok(ColorType::La16),
+        "Rgba16" => Ok(ColorType::Rgba16),
+        other => Err(BlockEncryptionError::UnsupportedColor(other.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> BlockKey {
+        BlockKey::new("dek-test", [7; KEY_LEN]).unwrap()
+    }
+
+    fn samples() -> Vec<u8> {
+        (0..64).map(|i| i as u8 * 3).collect()
+    }
+
+    fn block_key() -> [u8; KEY_LEN] {
+        derive_block_key(&key(), "image", 3)
+    }
+
+    #[test]
+    fn blocks_decrypt_to_their_samples() {
+        let encrypted = encrypt_block_data(&samples(), (4, 4), &key(), "image", 3);
+        assert_eq!(decrypt_block_data_with_key(&encrypted, &block_key(), "image", 3, (4, 4)).unwrap(), samples());
+
+        let mut key_store = KeyStore::new();
+        key_store.insert(key());
+        assert_eq!(decrypt_block_data(&encrypted, &key_store, "image", 3, (4, 4)).unwrap(), samples());
+    }
+
+    #[test]
+    fn changed_ciphertexts_and_tags_are_rejected() {
+        let encrypted = encrypt_block_data(&samples(), (4, 4), &key(), "image", 3);
+        let ciphertext_start = 1 + key().id().len() + NONCE_LEN;
+
+        // First byte of the ciphertext, first byte of the tag, and a byte of the nonce
+        for position in [ciphertext_start, encrypted.len() - TAG_LEN, ciphertext_start - 1] {
+            let mut tampered = encrypted.clone();
+            tampered[position] ^= 1;
+            assert!(matches!(
+                decrypt_block_data_with_key(&tampered, &block_key(), "image", 3, (4, 4)),
+                Err(BlockEncryptionError::AuthenticationFailed(3))
+            ));
+        }
+    }
+
+    #[test]
+    fn blocks_are_bound_to_their_associated_data_and_index() {
+        let encrypted = encrypt_block_data(&samples(), (4, 4), &key(), "image", 3);
+
+        // Another image id or other block dimensions change the associated data
+        assert!(decrypt_block_data_with_key(&encrypted, &block_key(), "other image", 3, (4, 4)).is_err());
+        assert!(decrypt_block_data_with_key(&encrypted, &block_key(), "image", 3, (8, 2)).is_err());
+
+        // A block moved to another index is rejected, with its own block key or the one of the index
+        assert!(decrypt_block_data_with_key(&encrypted, &block_key(), "image", 4, (4, 4)).is_err());
+        assert!(decrypt_block_data_with_key(&encrypted, &derive_block_key(&key(), "image", 4), "image", 4, (4, 4)).is_err());
+    }
+
+    #[test]
+    fn truncated_blocks_are_rejected() {
+        let encrypted = encrypt_block_data(&samples(), (4, 4), &key(), "image", 3);
+        let header_len = 1 + key().id().len() + NONCE_LEN + TAG_LEN;
+        assert!(matches!(
+            decrypt_block_data_with_key(&encrypted[..header_len - 1], &block_key(), "image", 3, (4, 4)),
+            Err(BlockEncryptionError::Truncated(_))
+        ));
+    }
+
+    #[test]
+    fn encrypting_a_block_twice_uses_distinct_nonces() {
+        let samples = [1u8; 64];
+        let first = encrypt_block_data(&samples, (4, 4), &key(), "image", 3);
+        let second = encrypt_block_data(&samples, (4, 4), &key(), "image", 3);
+
+        let (_, first_payload) = split_key_id(&first).unwrap();
+        let (_, second_payload) = split_key_id(&second).unwrap();
+        assert_ne!(first_payload[..NONCE_LEN], second_payload[..NONCE_LEN]);
+        assert_ne!(first_payload[NONCE_LEN..], second_payload[NONCE_LEN..]);
+    }
+
+    #[test]
+    fn macs_only_match_for_the_same_block() {
+        let samples = [1u8; 64];
+        let mac = block_mac(&samples, (4, 4), &key(), "image", 3);
+        assert_eq!(mac, block_mac(&samples, (4, 4), &key(), "image", 3));
+
+        let mut changed = samples;
+        changed[10] ^= 0x80;
+        assert_ne!(mac, block_mac(&changed, (4, 4), &key(), "image", 3));
+        assert_ne!(mac, block_mac(&samples, (4, 4), &key(), "image", 4));
+        assert_ne!(mac, block_mac(&samples, (4, 4), &key(), "other image", 3));
+        assert_ne!(mac, block_mac(&samples, (4, 4), &BlockKey::new("dek-other", [8; KEY_LEN]).unwrap(), "image", 3));
+    }
+
+    #[test]
+    fn sixteen_bit_blocks_round_trip_big_endian() {
+        let block = DynamicImage::ImageRgba16(ImageBuffer::from_fn(3, 2, |x, y| image::Rgba([0x0102 * (x as u16 + 1), 0xA0B0 + y as u16, 0xFF00, 0x00FF])));
+        let samples = block_samples(&block);
+        assert_eq!(samples[..8], [0x01, 0x02, 0xA0, 0xB0, 0xFF, 0x00, 0x00, 0xFF]);
+
+        let encrypted = encrypt_block_data(&samples, (3, 2), &key(), "image", 3);
+        let decrypted = decrypt_block_data_with_key(&encrypted, &block_key(), "image", 3, (3, 2)).unwrap();
+        let decoded = decode_block(decrypted, (3, 2), ColorType::Rgba16).unwrap();
+        assert_eq!(decoded.as_rgba16(), block.as_rgba16());
+
+        let gray = DynamicImage::ImageLuma16(ImageBuffer::from_fn(3, 2, |x, y| image::Luma([0x1234 + (x + 3 * y) as u16])));
+        assert_eq!(decode_block(block_samples(&gray), (3, 2), ColorType::L16).unwrap().as_luma16(), gray.as_luma16());
+    }
+
+    #[test]
+    fn square_blocks_of_another_image_id_do_not_share_associated_data() {
+        // A 32x32 block of an image id starting with 0x00000010 would encode like a 32x16 block
+        // if the height of square blocks or the length of the image id were omitted
+        let (square_id, strip_id) = ("\0\0\0\x10abc", "abc");
+        assert_ne!(associated_data(square_id, 3, (32, 32)), associated_data(strip_id, 3, (32, 16)));
+    }
+}
@@ -1,9 +1,16 @@
 // src/blockchain.rs
 
 use std::time::{SystemTime, UNIX_EPOCH};
-use sha2::{Digest, Sha256};
-use std::fmt::{self, Debug, Formatter};
-use hex;
+use crate::canonicalize::{CanonicalColor, Orientation};
+use crate::image_to_chunks::{BlockGrid, BlockLayout, BlockPartition, BlockRegion};
+use crate::feature_extractor::{ExtractorSpec, FeatureExtractor};
+use crate::key_sharing::KeyProtection;
+use crate::merkle_tree::{build_tree, TreeShape};
+use crate::perceptual_hash::LeafMode;
+use crate::pyramid::PyramidLevel;
+use crate::quadtree::QuadtreePartition;
+use crate::shifted_grid::ShiftedGrid;
+use image::ColorType;
 
 #[derive(Debug, Clone)]
 pub struct Blockchain {
@@ -13,7 +20,7 @@ pub struct Blockchain {
 #[derive(Debug, Clone)]
 pub struct Block {
     pub header: Header,
-    pub transaction: Transaction,
+    pub transaction: Option<Transaction>, // Registration recorded in the block, none in the genesis block
 }
 
 #[derive(Debug, Clone)]
@@ -21,6 +28,8 @@ pub struct Header {
     pub version: u32,
     pub prev_blockhash: String,
     pub merkle_root: String,
+    pub pyramid_roots: Vec<String>, // Merkle roots of the pyramid levels of the registration, coarsest first
+    pub shifted_root: Option<String>, // Merkle root of the shifted grid of the registration
     pub time: u32,
     pub nonce: u32,
 }
@@ -28,6 +37,85 @@ pub struct Header {
 #[derive(Debug, Clone)]
 pub struct Transaction {
     pub tx: Vec<String>,
+    pub cids: Vec<String>, // IPFS hashes of the encrypted blocks, in leaf order
+    pub supersedes: Option<String>, // Hash of the registration block replaced by this one
+    pub key_protection: KeyProtection, // Data key of the image, wrapped by a master key or split between custodians
+    pub block_color: ColorType, // Color type and depth of the encrypted blocks
+    pub extractor: ExtractorSpec, // Feature extractor and parameters the blocks were produced with
+    pub leaf_mode: LeafMode, // How the leaves fingerprint the blocks, with the perceptual threshold
+    pub orientation: Orientation, // EXIF orientation applied to the registered image before slicing
+    pub canonical_color: CanonicalColor, // Colour space of the registered pixels: sRGB, or the one of the file when not converted
+    pub grid: BlockGrid, // Dimensions of the features, block size and padding of the edge blocks
+    pub quadtree: Option<QuadtreePartition>, // Adaptive partition of the grid blocks, when blocks are not uniform
+    pub pyramid: Vec<PyramidLevel>, // Leaves at additional block sizes, coarsest first
+    pub shifted: Option<ShiftedGrid>, // Leaves on a second grid offset by half a block
+}
+
+impl Transaction {
+    // Block dimensions and padding of the registration
+    pub fn block_layout(&self) -> BlockLayout {
+        self.grid.layout
+    }
+
+    // How the suspect image must be sliced to compare its leaves with this registration
+    pub fn block_partition(&self) -> BlockPartition {
+        match &self.quadtree {
+            Some(partition) => BlockPartition::Quadtree(partition.clone()),
+            None => BlockPartition::Grid(self.block_layout()),
+        }
+    }
+
+    // Width and height of each block, in leaf order
+    pub fn block_dimensions(&self) -> Vec<(u32, u32)> {
+        self.block_regions().iter().map(BlockRegion::dimensions).collect()
+    }
+
+    // Regions of the image covered by each block, in leaf order
+    pub fn block_regions(&self) -> Vec<BlockRegion> {
+        match &self.quadtree {
+            Some(partition) => partition.leaves(),
+            None => self.grid.regions(),
+        }
+    }
+
+    // IPFS hash of the encrypted block `index`
+    pub fn block_cid(&self, index: usize) -> Option<&str> {
+        self.cids.get(index).map(String::as_str)
+    }
+
+    // Shape of the merkle tree of adaptive partitions, balanced otherwise
+    pub fn tree_shape(&self) -> Option<TreeShape> {
+        self.quadtree.as_ref().map(QuadtreePartition::tree_shape)
+    }
+
+    // The feature extractor to use when verifying an image against this registration
+    pub fn feature_extractor(&self) -> Box<dyn FeatureExtractor> {
+        self.extractor.extractor()
+    }
+}
+
+// Registration of exact leaves of 8-bit RGBA MSB blocks on `grid`, with a wrapped data key
+#[cfg(test)]
+pub fn test_registration(grid: BlockGrid, tx: Vec<String>, cids: Vec<String>) -> Transaction {
+    use crate::image_to_msb::MsbOptions;
+    use crate::key_store::{generate_data_key, wrap_data_key, BlockKey, KEY_LEN};
+
+    let master_key = BlockKey::new("department", [1; KEY_LEN]).unwrap();
+    Transaction {
+        tx,
+        cids,
+        supersedes: None,
+        key_protection: KeyProtection::Wrapped(wrap_data_key(&generate_data_key(), &master_key, "image")),
+        block_color: ColorType::Rgba8,
+        extractor: ExtractorSpec::Msb(MsbOptions { bits: 1, include_alpha: false, luma: None }),
+        leaf_mode: LeafMode::Exact,
+        orientation: Orientation::Normal,
+        canonical_color: CanonicalColor::Srgb,
+        grid,
+        quadtree: None,
+        pyramid: Vec::new(),
+        shifted: None,
+    }
 }
 
 impl Blockchain {
@@ -37,37 +125,64 @@ impl Blockchain {
                 version: 1,
                 prev_blockhash: "0".to_string(),
                 merkle_root: "0".to_string(),
+                pyramid_roots: Vec::new(),
+                shifted_root: None,
                 time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32,
                 nonce: 0,
             },
-            transaction: Transaction {
-                tx: vec![],
-            },
+            transaction: None,
         };
         Blockchain {
             chain: vec![genesis_block],
         }
     }
 
-    pub fn add_block(&mut self, merkle_root: String, transactions: Vec<String>) {
+    // The roots of the pyramid levels and of the shifted grid are computed from the transaction,
+    // so that the header commits to every tree verification compares against
+    pub fn add_block(&mut self, merkle_root: String, transaction: Transaction) {
         let prev_block = self.chain.last().unwrap();
         let prev_blockhash = calculate_hash(&prev_block.header);
+        let pyramid_roots = transaction
+            .pyramid
+            .iter()
+            .map(|level| build_tree(level.leaves.clone(), None).root_hex().unwrap_or_default())
+            .collect();
+        let shifted_root = transaction.shifted.as_ref().and_then(|shifted| build_tree(shifted.leaves.clone(), None).root_hex());
 
         let new_block = Block {
             header: Header {
                 version: 1,
                 prev_blockhash,
                 merkle_root,
+                pyramid_roots,
+                shifted_root,
                 time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32,
                 nonce: 0,
             },
-            transaction: Transaction {
-                tx: transactions,
-            },
+            transaction: Some(transaction),
         };
         self.chain.push(new_block);
     }
 
+    pub fn find_block(&self, block_hash: &str) -> Option<&Block> {
+        self.chain.iter().find(|block| calculate_hash(&block.header) == block_hash)
+    }
+
+    // Follow the supersedes links from a registration to its most recent replacement
+    pub fn latest_registration(&self, block_hash: &str) -> Option<&Block> {
+        let mut current = self.find_block(block_hash)?;
+        let mut current_hash = block_hash.to_string();
+        while let Some(next) = self
+            .chain
+            .iter()
+            .find(|block| block.transaction.as_ref().is_some_and(|transaction| transaction.supersedes.as_deref() == Some(current_hash.as_str())))
+        {
+            current = next;
+            current_hash = calculate_hash(&next.header);
+        }
+        Some(current)
+    }
+
     pub fn print_blockchain(&self) {
         for block in &self.chain {
             println!("{:?}", block);
@@ -79,17 +194,19 @@ impl Blockchain {
 
 pub fn calculate_hash(header: &Header) -> String {
     // Simple hash function for example purposes
-    let header_string = format!(
+    let mut header_string = format!(
         "{}{}{}{}{}",
         header.version, header.prev_blockhash, header.merkle_root, header.time, header.nonce
     );
+    header_string.push_str(&format!("pyramid{}", header.pyramid_roots.join(",")));
+    if let Some(shifted_root) = &header.shifted_root {
+        header_string.push_str(&format!("shifted{}", shifted_root));
+    }
     format!("{:x}", md5::compute(header_string))
 }
 pub fn return_transaction(blockchain:&Blockchain, block_hash: &str) -> Vec<String> {
-    for block in &blockchain.chain {
-        if calculate_hash(&block.header) == block_hash {
-            return block.transaction.tx.clone();
-        }
+    match blockchain.find_block(block_hash) {
+        Some(Block { transaction: Some(transaction), .. }) => transaction.tx.clone(),
+        _ => Vec::new(), // Return an empty vector if no registration matches
     }
-    Vec::new() // Return an empty vector if no block matches
 }
\ No newline at end of file
@@ -0,0 +1,521 @@
+// src/canonicalize.rs
+
+use crate::image_to_msb::is_16_bit;
+use exif::{In, Reader, Tag, Value};
+use image::{DynamicImage, GenericImageView, ImageBuffer};
+use qcms::{DataType, Intent, Profile, Transform};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Cursor;
+use thiserror::Error;
+
+// TIFF tag holding an embedded ICC profile
+pub const TIFF_ICC_PROFILE: u16 = 34675;
+
+// Identifier of the JPEG APP2 segments carrying an ICC profile
+const JPEG_ICC_MARKER: &[u8] = b"ICC_PROFILE\0";
+
+#[derive(Debug, Error)]
+pub enum CanonicalizeError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Decode(#[from] image::ImageError),
+}
+
+// Colour space the pixels of an image file are encoded in
+#[derive(Debug, Clone, PartialEq)]
+enum ColorSource {
+    Srgb,
+    Icc(Vec<u8>),
+    // PNG gAMA chunk: decoding exponent, with the cHRM white point and primaries when present
+    Gamma { gamma: f32, chromaticities: Option<[f64; 8]> },
+}
+
+impl ColorSource {
+    fn describe(&self) -> String {
+        match self {
+            ColorSource::Srgb => "sRGB".to_string(),
+            ColorSource::Icc(profile) => format!("ICC profile {}", hex::encode(&Sha256::digest(profile)[..8])),
+            ColorSource::Gamma { gamma, chromaticities: None } => format!("PNG gamma {}", gamma),
+            ColorSource::Gamma { gamma, chromaticities: Some(values) } => format!("PNG gamma {} with chromaticities {:?}", gamma, values),
+        }
+    }
+}
+
+// Colour space of the canonical pixels of an image, recorded in its registration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CanonicalColor {
+    Srgb,              // Encoded in sRGB, or converted to it
+    AsDecoded(String), // Kept in the colour space of the file, named by its profile fingerprint or gamma
+}
+
+// Load an image in a canonical form that only depends on its visual content: decoded to RGBA,
+// converted to sRGB from its embedded ICC profile or PNG gamma, and with its EXIF orientation
+// applied. Container-level metadata is not carried over.
+pub fn load_canonical_image(img_path: &str) -> Result<DynamicImage, CanonicalizeError> {
+    Ok(canonicalize(&fs::read(img_path)?, img_path)?.0)
+}
+
+// Colour space the canonical pixels of an image file are in. Only images in another colour
+// space than sRGB are decoded.
+pub fn canonical_color(img_path: &str) -> Result<CanonicalColor, CanonicalizeError> {
+    let data = fs::read(img_path)?;
+    Ok(match color_source(&data) {
+        ColorSource::Srgb => CanonicalColor::Srgb,
+        _ => canonicalize(&data, img_path)?.1,
+    })
+}
+
+fn canonicalize(data: &[u8], img_path: &str) -> Result<(DynamicImage, CanonicalColor), CanonicalizeError> {
+    let img = image::load_from_memory(data)?;
+
+    // The colour management engine only converts 8-bit samples: 16-bit images keep their depth,
+    // and their own colour space, rather than being reduced to 8 bits
+    let (img, color) = match color_source(data) {
+        ColorSource::Srgb => (img, CanonicalColor::Srgb),
+        source if is_16_bit(&img) => {
+            eprintln!("Keeping the 16-bit pixels of {} in their {}, not converted to sRGB", img_path, source.describe());
+            (img, CanonicalColor::AsDecoded(source.describe()))
+        }
+        source => match convert_to_srgb(&img, &source) {
+            Some(converted) => (converted, CanonicalColor::Srgb),
+            None => {
+                eprintln!("Failed to convert {} from its {} to sRGB, keeping its pixels as decoded", img_path, source.describe());
+                (img, CanonicalColor::AsDecoded(source.describe()))
+            }
+        },
+    };
+    let img = orientation(data).apply(img);
+
+    // Palette, gray and RGB encodings of the same pixels give the same buffer
+    let img = if is_16_bit(&img) {
+        DynamicImage::ImageRgba16(img.to_rgba16())
+    } else {
+        DynamicImage::ImageRgba8(img.to_rgba8())
+    };
+    Ok((img, color))
+}
+
+// EXIF orientation: how the stored pixels are transformed to be displayed upright.
+// Phones store portrait photos as landscape pixels plus an orientation, while re-exported
+// copies often bake the rotation into the pixels; both give the same upright image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    Normal,
+    FlipHorizontal,
+    Rotate180,
+    FlipVertical,
+    Transpose,
+    Rotate90,
+    Transverse,
+    Rotate270,
+}
+
+impl Orientation {
+    // Values outside 1 to 8 are invalid and treated as no orientation
+    pub fn from_exif(value: u32) -> Self {
+        match value {
+            2 => Orientation::FlipHorizontal,
+            3 => Orientation::Rotate180,
+            4 => Orientation::FlipVertical,
+            5 => Orientation::Transpose,
+            6 => Orientation::Rotate90,
+            7 => Orientation::Transverse,
+            8 => Orientation::Rotate270,
+            _ => Orientation::Normal,
+        }
+    }
+
+    // Rotate and flip the pixels so that they are displayed upright without metadata
+    pub fn apply(self, img: DynamicImage) -> DynamicImage {
+        match self {
+            Orientation::Normal => img,
+            Orientation::FlipHorizontal => img.fliph(),
+            Orientation::Rotate180 => img.rotate180(),
+            Orientation::FlipVertical => img.flipv(),
+            Orientation::Transpose => img.rotate90().fliph(),
+            Orientation::Rotate90 => img.rotate90(),
+            Orientation::Transverse => img.rotate270().fliph(),
+            Orientation::Rotate270 => img.rotate270(),
+        }
+    }
+}
+
+// EXIF orientation of an image file
+pub fn read_orientation(img_path: &str) -> Result<Orientation, CanonicalizeError> {
+    Ok(orientation(&fs::read(img_path)?))
+}
+
+fn orientation(data: &[u8]) -> Orientation {
+    Reader::new()
+        .read_from_container(&mut Cursor::new(data))
+        .ok()
+        .and_then(|exif| exif.get_field(Tag::Orientation, In::PRIMARY).and_then(|field| field.value.get_uint(0)))
+        .map_or(Orientation::Normal, Orientation::from_exif)
+}
+
+fn color_source(data: &[u8]) -> ColorSource {
+    let profile = if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return png_color_source(data);
+    } else if data.starts_with(&[0xFF, 0xD8]) {
+        jpeg_icc_profile(data)
+    } else if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+        tiff_icc_profile(data)
+    } else {
+        None
+    };
+
+    // Images without colour information are assumed to be sRGB
+    profile.map_or(ColorSource::Srgb, ColorSource::Icc)
+}
+
+// iCCP takes precedence over sRGB, which takes precedence over gAMA and cHRM
+fn png_color_source(data: &[u8]) -> ColorSource {
+    let mut gamma = None;
+    let mut chromaticities = None;
+    let mut srgb = false;
+
+    let mut rest = &data[8..];
+    while rest.len() >= 12 {
+        let length = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let (chunk_type, chunk) = (&rest[4..8], &rest[8..]);
+        if chunk.len() < length + 4 {
+            break;
+        }
+        let chunk = &chunk[..length];
+
+        match chunk_type {
+            b"iCCP" => {
+                // Profile name, null separator, compression method, zlib stream
+                let profile = chunk
+                    .iter()
+                    .position(|&byte| byte == 0)
+                    .and_then(|name_end| chunk.get(name_end + 2..))
+                    .and_then(|compressed| miniz_oxide::inflate::decompress_to_vec_zlib(compressed).ok());
+                if let Some(profile) = profile {
+                    return ColorSource::Icc(profile);
+                }
+            }
+            b"sRGB" => srgb = true,
+            b"gAMA" if length == 4 => {
+                let encoding = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                if encoding > 0 {
+                    gamma = Some(100_000.0 / encoding as f32);
+                }
+            }
+            b"cHRM" if length == 32 => {
+                let mut values = [0f64; 8];
+                for (value, bytes) in values.iter_mut().zip(chunk.chunks_exact(4)) {
+                    *value = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64 / 100_000.0;
+                }
+                chromaticities = Some(values);
+            }
+            b"IDAT" | b"IEND" => break,
+            _ => {}
+        }
+        rest = &rest[12 + length..];
+    }
+
+    match gamma {
+        Some(gamma) if !srgb => ColorSource::Gamma { gamma, chromaticities },
+        _ => ColorSource::Srgb,
+    }
+}
+
+// ICC profile split across APP2 segments, reassembled in sequence order
+fn jpeg_icc_profile(data: &[u8]) -> Option<Vec<u8>> {
+    let mut chunks = Vec::new();
+    let mut position = 2;
+    while position + 4 <= data.len() && data[position] == 0xFF {
+        let marker = data[position + 1];
+        // Start of scan: no metadata segments follow
+        if marker == 0xDA {
+            break;
+        }
+        let length = u16::from_be_bytes([data[position + 2], data[position + 3]]) as usize;
+        let segment = data.get(position + 4..position + 2 + length)?;
+
+        if marker == 0xE2 && segment.starts_with(JPEG_ICC_MARKER) && segment.len() > JPEG_ICC_MARKER.len() + 2 {
+            let sequence = segment[JPEG_ICC_MARKER.len()];
+            chunks.push((sequence, &segment[JPEG_ICC_MARKER.len() + 2..]));
+        }
+        position += 2 + length;
+    }
+
+    if chunks.is_empty() {
+        return None;
+    }
+    chunks.sort_by_key(|&(sequence, _)| sequence);
+    Some(chunks.into_iter().flat_map(|(_, chunk)| chunk.iter().copied()).collect())
+}
+
+fn tiff_icc_profile(data: &[u8]) -> Option<Vec<u8>> {
+    let exif = Reader::new().read_raw(data.to_vec()).ok()?;
+    let field = exif.get_field(Tag(exif::Context::Tiff, TIFF_ICC_PROFILE), In::PRIMARY)?;
+    match &field.value {
+        Value::Undefined(profile, _) | Value::Byte(profile) => Some(profile.clone()),
+        _ => None,
+    }
+}
+
+fn input_profile(source: &ColorSource) -> Option<Box<Profile>> {
+    match source {
+        ColorSource::Srgb => Some(Profile::new_sRGB()),
+        ColorSource::Icc(profile) => Profile::new_from_slice(profile, false),
+        ColorSource::Gamma { gamma, chromaticities } => {
+            // Without cHRM the primaries and white point are those of sRGB
+            let [wx, wy, rx, ry, gx, gy, bx, by] = chromaticities.unwrap_or([0.3127, 0.3290, 0.64, 0.33, 0.30, 0.60, 0.15, 0.06]);
+            let xy_y = |x, y| qcms::CIE_xyY { x, y, Y: 1.0 };
+            let primaries = qcms::CIE_xyYTRIPLE { red: xy_y(rx, ry), green: xy_y(gx, gy), blue: xy_y(bx, by) };
+            Profile::new_rgb_with_gamma_set(xy_y(wx, wy), primaries, *gamma, *gamma, *gamma)
+        }
+    }
+}
+
+// Whether an ICC profile describes a grayscale colour space
+fn is_gray_profile(source: &ColorSource) -> bool {
+    matches!(source, ColorSource::Icc(profile) if profile.get(16..20) == Some(b"GRAY"))
+}
+
+// Convert the 8-bit pixels of an image to sRGB, or None when its colour space cannot be converted
+fn convert_to_srgb(img: &DynamicImage, source: &ColorSource) -> Option<DynamicImage> {
+    let srgb = Profile::new_sRGB();
+    let profile = input_profile(source)?;
+
+    let (width, height) = (img.width(), img.height());
+    let converted = if is_gray_profile(source) {
+        let gray = img.to_luma_alpha8();
+        let mut rgba = vec![0u8; (width * height * 4) as usize];
+        Transform::new_to(&profile, &srgb, DataType::GrayA8, DataType::RGBA8, Intent::Perceptual).map(|transform| {
+            transform.convert(gray.as_raw(), &mut rgba);
+            rgba
+        })
+    } else {
+        let mut rgba = img.to_rgba8().into_raw();
+        Transform::new(&profile, &srgb, DataType::RGBA8, Intent::Perceptual).map(|transform| {
+            transform.apply(&mut rgba);
+            rgba
+        })
+    };
+
+    converted.and_then(|rgba| ImageBuffer::from_raw(width, height, rgba)).map(DynamicImage::ImageRgba8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageOutputFormat, Rgba};
+
+    // PNG signature followed by the given chunks
+    fn png(chunks: &[(&[u8], Vec<u8>)]) -> Vec<u8> {
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        for (chunk_type, chunk) in chunks {
+            data.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+            let start = data.len();
+            data.extend_from_slice(chunk_type);
+            data.extend_from_slice(chunk);
+            let crc = crc32(&data[start..]);
+            data.extend_from_slice(&crc.to_be_bytes());
+        }
+        data
+    }
+
+    // CRC-32 of a PNG chunk type and data, checked by the decoder
+    fn crc32(bytes: &[u8]) -> u32 {
+        !bytes.iter().fold(!0u32, |crc, &byte| {
+            (0..8).fold(crc ^ byte as u32, |crc, _| if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 })
+        })
+    }
+
+    fn iccp(profile: &[u8]) -> Vec<u8> {
+        let mut chunk = b"icc\0\0".to_vec();
+        chunk.extend(miniz_oxide::deflate::compress_to_vec_zlib(profile, 6));
+        chunk
+    }
+
+    fn gama(encoding: u32) -> Vec<u8> {
+        encoding.to_be_bytes().to_vec()
+    }
+
+    // SOI followed by the given marker segments and a start of scan
+    fn jpeg(segments: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        let mut data = vec![0xFF, 0xD8];
+        for (marker, segment) in segments {
+            data.extend_from_slice(&[0xFF, *marker]);
+            data.extend_from_slice(&(segment.len() as u16 + 2).to_be_bytes());
+            data.extend_from_slice(segment);
+        }
+        data.extend_from_slice(&[0xFF, 0xDA, 0, 2]);
+        data
+    }
+
+    fn icc_segment(sequence: u8, count: u8, chunk: &[u8]) -> (u8, Vec<u8>) {
+        let mut segment = JPEG_ICC_MARKER.to_vec();
+        segment.extend_from_slice(&[sequence, count]);
+        segment.extend_from_slice(chunk);
+        (0xE2, segment)
+    }
+
+    #[test]
+    fn png_profiles_take_precedence_over_srgb_and_gamma() {
+        let data = png(&[(b"gAMA", gama(45455)), (b"sRGB", vec![0]), (b"iCCP", iccp(b"profile"))]);
+        assert_eq!(png_color_source(&data), ColorSource::Icc(b"profile".to_vec()));
+        assert_eq!(png_color_source(&png(&[(b"gAMA", gama(45455)), (b"sRGB", vec![0])])), ColorSource::Srgb);
+    }
+
+    #[test]
+    fn png_gamma_is_read_with_its_chromaticities() {
+        let chromaticities = [31270u32, 32900, 64000, 33000, 30000, 60000, 15000, 6000];
+        let chrm = chromaticities.iter().flat_map(|value| value.to_be_bytes()).collect();
+        match png_color_source(&png(&[(b"gAMA", gama(50000)), (b"cHRM", chrm)])) {
+            ColorSource::Gamma { gamma, chromaticities: Some(values) } => {
+                assert_eq!(gamma, 2.0);
+                assert_eq!(values, [0.3127, 0.329, 0.64, 0.33, 0.3, 0.6, 0.15, 0.06]);
+            }
+            other => panic!("unexpected colour source {:?}", other),
+        }
+        assert_eq!(png_color_source(&png(&[(b"gAMA", gama(100_000))])), ColorSource::Gamma { gamma: 1.0, chromaticities: None });
+    }
+
+    #[test]
+    fn malformed_png_chunks_are_ignored() {
+        // A gAMA chunk of the wrong length, a zero gamma and a corrupted profile
+        let mut corrupted = iccp(b"profile");
+        corrupted.truncate(corrupted.len() - 3);
+        let data = png(&[(b"gAMA", vec![0, 1, 0]), (b"gAMA", gama(0)), (b"iCCP", corrupted)]);
+        assert_eq!(png_color_source(&data), ColorSource::Srgb);
+
+        // Chunks after the image data are not read
+        assert_eq!(png_color_source(&png(&[(b"IDAT", vec![0; 8]), (b"gAMA", gama(45455))])), ColorSource::Srgb);
+    }
+
+    #[test]
+    fn truncated_png_chunks_are_ignored() {
+        let data = png(&[(b"gAMA", gama(45455))]);
+        for length in 8..data.len() {
+            assert_eq!(png_color_source(&data[..length]), ColorSource::Srgb, "{} bytes", length);
+        }
+
+        // A chunk claiming more bytes than the file holds
+        let mut data = png(&[(b"iCCP", iccp(b"profile"))]);
+        data[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(png_color_source(&data), ColorSource::Srgb);
+    }
+
+    #[test]
+    fn jpeg_profiles_are_reassembled_in_sequence_order() {
+        let data = jpeg(&[icc_segment(2, 2, b" world"), (0xE1, b"Exif\0\0".to_vec()), icc_segment(1, 2, b"hello")]);
+        assert_eq!(jpeg_icc_profile(&data), Some(b"hello world".to_vec()));
+        assert_eq!(jpeg_icc_profile(&jpeg(&[(0xE2, b"other".to_vec())])), None);
+    }
+
+    #[test]
+    fn truncated_jpeg_segments_are_rejected() {
+        let data = jpeg(&[icc_segment(1, 1, b"profile")]);
+        let segment_end = data.len() - 4;
+        for length in 2..segment_end {
+            assert_eq!(jpeg_icc_profile(&data[..length]), None, "{} bytes", length);
+        }
+
+        // Segment lengths shorter than the length field itself
+        for length in [0u8, 1] {
+            let mut data = data.clone();
+            data[4..6].copy_from_slice(&[0, length]);
+            assert_eq!(jpeg_icc_profile(&data), None);
+        }
+    }
+
+    // APP1 segment holding a big-endian TIFF structure with a single Orientation entry
+    fn exif_orientation_segment(value: u16) -> (u8, Vec<u8>) {
+        let mut segment = b"Exif\0\0MM\0*".to_vec();
+        segment.extend_from_slice(&8u32.to_be_bytes());
+        segment.extend_from_slice(&1u16.to_be_bytes());
+        segment.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1]); // Orientation, SHORT, one value
+        segment.extend_from_slice(&value.to_be_bytes());
+        segment.extend_from_slice(&[0, 0, 0, 0, 0, 0]); // Padding of the value and no next IFD
+        (0xE1, segment)
+    }
+
+    #[test]
+    fn orientations_display_the_stored_pixels_upright() {
+        // Stored pixels:
+        // 1 2 3
+        // 4 5 6
+        let stored = DynamicImage::ImageLuma8(ImageBuffer::from_fn(3, 2, |x, y| image::Luma([(1 + x + 3 * y) as u8])));
+        let table: [(u32, Orientation, &[&[u8]]); 8] = [
+            (1, Orientation::Normal, &[&[1, 2, 3], &[4, 5, 6]]),
+            (2, Orientation::FlipHorizontal, &[&[3, 2, 1], &[6, 5, 4]]),
+            (3, Orientation::Rotate180, &[&[6, 5, 4], &[3, 2, 1]]),
+            (4, Orientation::FlipVertical, &[&[4, 5, 6], &[1, 2, 3]]),
+            (5, Orientation::Transpose, &[&[1, 4], &[2, 5], &[3, 6]]),
+            (6, Orientation::Rotate90, &[&[4, 1], &[5, 2], &[6, 3]]),
+            (7, Orientation::Transverse, &[&[6, 3], &[5, 2], &[4, 1]]),
+            (8, Orientation::Rotate270, &[&[3, 6], &[2, 5], &[1, 4]]),
+        ];
+        for (value, orientation, upright) in table {
+            assert_eq!(Orientation::from_exif(value), orientation);
+            let displayed = orientation.apply(stored.clone()).to_luma8();
+            let rows: Vec<Vec<u8>> = displayed.rows().map(|row| row.map(|pixel| pixel[0]).collect()).collect();
+            assert_eq!(rows, upright, "orientation {}", value);
+        }
+
+        for invalid in [0, 9] {
+            assert_eq!(Orientation::from_exif(invalid), Orientation::Normal);
+        }
+    }
+
+    #[test]
+    fn jpeg_orientation_is_read_from_exif() {
+        let path = std::env::temp_dir().join(format!("canonicalize_orientation_{}.jpg", std::process::id()));
+        fs::write(&path, jpeg(&[exif_orientation_segment(6)])).unwrap();
+        let rotated = read_orientation(path.to_str().unwrap()).unwrap();
+        fs::write(&path, jpeg(&[icc_segment(1, 1, b"profile")])).unwrap();
+        let unrotated = read_orientation(path.to_str().unwrap()).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(rotated, Orientation::Rotate90);
+        assert_eq!(unrotated, Orientation::Normal);
+    }
+
+    // Path of a PNG of `img` declaring linear gamma by a gAMA chunk after its header
+    fn linear_png(img: &DynamicImage, name: &str) -> std::path::PathBuf {
+        let mut encoded = Vec::new();
+        img.write_to(&mut encoded, ImageOutputFormat::Png).unwrap();
+        let header_end = 8 + 12 + 13;
+        let gama_chunk = png(&[(b"gAMA", gama(100_000))]);
+        encoded.splice(header_end..header_end, gama_chunk[8..].iter().copied());
+
+        let path = std::env::temp_dir().join(format!("canonicalize_{}_{}.png", name, std::process::id()));
+        fs::write(&path, &encoded).unwrap();
+        path
+    }
+
+    #[test]
+    fn eight_bit_images_are_converted_to_srgb() {
+        let gray = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(4, 4, Rgba([0x80, 0x80, 0x80, 0xFF])));
+        let path = linear_png(&gray, "8_bit");
+        let canonical = load_canonical_image(path.to_str().unwrap()).unwrap();
+        let color = canonical_color(path.to_str().unwrap()).unwrap();
+        fs::remove_file(path).unwrap();
+
+        // Linear mid gray is lighter in sRGB
+        let DynamicImage::ImageRgba8(canonical) = canonical else { panic!("converted image is not 8-bit RGBA") };
+        assert!(canonical.pixels().all(|pixel| pixel[0] > 0xB0 && pixel[3] == 0xFF), "{:?}", canonical.get_pixel(0, 0));
+        assert_eq!(color, CanonicalColor::Srgb);
+    }
+
+    #[test]
+    fn sixteen_bit_images_keep_their_depth_and_colour_space() {
+        // Samples have equal bytes, whatever the byte order the encoder writes them in
+        let gray = DynamicImage::ImageRgba16(ImageBuffer::from_pixel(4, 4, Rgba([0x8080, 0x8080, 0x8080, 0xFFFF])));
+        let path = linear_png(&gray, "16_bit");
+        let canonical = load_canonical_image(path.to_str().unwrap()).unwrap();
+        let color = canonical_color(path.to_str().unwrap()).unwrap();
+        fs::remove_file(path).unwrap();
+
+        let DynamicImage::ImageRgba16(canonical) = canonical else { panic!("16-bit image was reduced to 8 bits") };
+        assert_eq!(canonical.as_raw(), gray.as_rgba16().unwrap().as_raw());
+        assert_eq!(color, CanonicalColor::AsDecoded("PNG gamma 1".to_string()));
+    }
+}
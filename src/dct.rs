@@ -0,0 +1,97 @@
+// src/dct.rs
+
+use std::f32::consts::PI;
+
+fn basis(k: usize, i: usize, n: usize) -> f32 {
+    (PI * (2 * i + 1) as f32 * k as f32 / (2 * n) as f32).cos()
+}
+
+// The `keep` x `keep` lowest frequencies of the 2D DCT-II of an `n` x `n` row-major block.
+// Coefficients are row-major by vertical then horizontal frequency, without normalization.
+pub fn dct_2d(samples: &[f32], n: usize, keep: usize) -> Vec<f32> {
+    // Transform the rows, then the columns of the result
+    let mut rows = vec![0f32; n * keep];
+    for y in 0..n {
+        for u in 0..keep {
+            rows[y * keep + u] = (0..n).map(|x| samples[y * n + x] * basis(u, x, n)).sum();
+        }
+    }
+
+    let mut coefficients = vec![0f32; keep * keep];
+    for v in 0..keep {
+        for u in 0..keep {
+            coefficients[v * keep + u] = (0..n).map(|y| rows[y * keep + u] * basis(v, y, n)).sum();
+        }
+    }
+    coefficients
+}
+
+// Factor making frequency `k` of an `n` point DCT orthonormal
+pub fn dct_scale(k: usize, n: usize) -> f32 {
+    if k == 0 {
+        (1.0 / n as f32).sqrt()
+    } else {
+        (2.0 / n as f32).sqrt()
+    }
+}
+
+// Inverse of the orthonormal 2D DCT-II of an `n` x `n` block
+pub fn idct_2d(coefficients: &[f32], n: usize) -> Vec<f32> {
+    let mut samples = vec![0f32; n * n];
+    for y in 0..n {
+        for x in 0..n {
+            samples[y * n + x] = (0..n)
+                .flat_map(|v| (0..n).map(move |u| (u, v)))
+                .map(|(u, v)| dct_scale(u, n) * dct_scale(v, n) * coefficients[v * n + u] * basis(u, x, n) * basis(v, y, n))
+                .sum();
+        }
+    }
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Orthonormal coefficients of a block, as `idct_2d` expects them
+    fn orthonormal_dct(samples: &[f32], n: usize) -> Vec<f32> {
+        let mut coefficients = dct_2d(samples, n, n);
+        for v in 0..n {
+            for u in 0..n {
+                coefficients[v * n + u] *= dct_scale(u, n) * dct_scale(v, n);
+            }
+        }
+        coefficients
+    }
+
+    #[test]
+    fn inverse_transform_restores_the_samples() {
+        let samples: Vec<f32> = (0..64).map(|i| ((i * 37) % 255) as f32 - 128.0).collect();
+        let restored = idct_2d(&orthonormal_dct(&samples, 8), 8);
+        for (sample, restored) in samples.iter().zip(&restored) {
+            assert!((sample - restored).abs() < 1e-2, "{} restored as {}", sample, restored);
+        }
+    }
+
+    #[test]
+    fn constant_blocks_only_have_a_dc_coefficient() {
+        let coefficients = dct_2d(&[5.0; 64], 8, 8);
+        assert!((coefficients[0] - 5.0 * 64.0).abs() < 1e-3);
+        assert!(coefficients[1..].iter().all(|coefficient| coefficient.abs() < 1e-3));
+
+        // Orthonormal DC of an n x n block is n times its value
+        assert!((orthonormal_dct(&[5.0; 64], 8)[0] - 40.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn only_the_lowest_frequencies_are_kept() {
+        let samples: Vec<f32> = (0..64).map(|i| (i % 8 * 3 + i / 8) as f32).collect();
+        let full = dct_2d(&samples, 8, 8);
+        let low = dct_2d(&samples, 8, 3);
+        for v in 0..3 {
+            for u in 0..3 {
+                assert!((low[v * 3 + u] - full[v * 8 + u]).abs() < 1e-3);
+            }
+        }
+    }
+}
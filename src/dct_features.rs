@@ -0,0 +1,227 @@
+// src/dct_features.rs
+
+use crate::canonicalize::load_canonical_image;
+use crate::dct::{dct_2d, dct_scale, idct_2d};
+use image::{DynamicImage, GenericImageView, GrayImage, ImageBuffer, Luma};
+
+// Size of the JPEG-style cells the coefficients are computed on.
+// Blocks must be a multiple of it so that cells never straddle two blocks.
+pub const DCT_CELL: u32 = 8;
+
+// Offset stored with each quantized coefficient so that it fits an unsigned 16-bit sample
+const COEFFICIENT_OFFSET: f32 = 32768.0;
+
+// JPEG luminance quantization table (ITU-T T.81, Annex K), row-major
+const LUMINANCE_QUANTIZATION: [f32; 64] = [
+    16.0, 11.0, 10.0, 16.0, 24.0, 40.0, 51.0, 61.0,
+    12.0, 12.0, 14.0, 19.0, 26.0, 58.0, 60.0, 55.0,
+    14.0, 13.0, 16.0, 24.0, 40.0, 57.0, 69.0, 56.0,
+    14.0, 17.0, 22.0, 29.0, 51.0, 87.0, 80.0, 62.0,
+    18.0, 22.0, 37.0, 56.0, 68.0, 109.0, 103.0, 77.0,
+    24.0, 35.0, 55.0, 64.0, 81.0, 104.0, 113.0, 92.0,
+    49.0, 64.0, 78.0, 87.0, 103.0, 121.0, 120.0, 101.0,
+    72.0, 92.0, 95.0, 98.0, 112.0, 100.0, 103.0, 99.0,
+];
+
+// Row-major position of the coefficients of a cell, from the lowest frequency up
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5,
+    12, 19, 26, 33, 40, 48, 41, 34, 27, 20, 13, 6, 7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+// Parameters of the DCT representation that is authenticated
+#[derive(Debug, Clone, Copy)]
+pub struct DctOptions {
+    pub coefficients: u8, // Number of low frequency coefficients kept per cell in zig-zag order, from 1 to 64
+    pub quantization: f32, // Scale of the JPEG quantization table: larger is coarser and more robust
+}
+
+impl DctOptions {
+    // Quantization step of each row-major coefficient of a cell, zero for dropped coefficients
+    fn steps(&self) -> [f32; 64] {
+        assert!((1..=64).contains(&self.coefficients), "coefficients must be between 1 and 64, got {}", self.coefficients);
+        assert!(self.quantization > 0.0, "quantization must be positive, got {}", self.quantization);
+
+        let mut steps = [0f32; 64];
+        for &position in &ZIGZAG[..self.coefficients as usize] {
+            steps[position] = LUMINANCE_QUANTIZATION[position] * self.quantization;
+        }
+        steps
+    }
+}
+
+// Replace the pixels of every 8x8 cell of the luminance by its quantized low frequency
+// DCT coefficients. Coefficient (u, v) of a cell is stored at pixel (u, v) of the cell, so
+// blocks sliced from the result hold the coefficients of the same area of the image.
+// The image is padded to whole cells by repeating its last row and column.
+pub fn extract_dct_features(img_path: &str, options: DctOptions) -> DynamicImage {
+    // Load the image from a file
+    let img = load_canonical_image(img_path).expect("Failed to open image").to_luma8();
+    dct_features(&img, options)
+}
+
+// Quantized DCT coefficients of a luminance image, or of a tile of one starting on a cell boundary
+pub fn dct_features(img: &GrayImage, options: DctOptions) -> DynamicImage {
+    let steps = options.steps();
+    let n = DCT_CELL as usize;
+
+    let (width, height) = img.dimensions();
+    let padded_width = width.div_ceil(DCT_CELL) * DCT_CELL;
+    let padded_height = height.div_ceil(DCT_CELL) * DCT_CELL;
+
+    let mut features = ImageBuffer::new(padded_width, padded_height);
+    for cell_y in (0..padded_height).step_by(n) {
+        for cell_x in (0..padded_width).step_by(n) {
+            // Level shift the samples around zero as JPEG does
+            let mut samples = vec![0f32; n * n];
+            for y in 0..n {
+                for x in 0..n {
+                    let pixel = img.get_pixel((cell_x + x as u32).min(width - 1), (cell_y + y as u32).min(height - 1));
+                    samples[y * n + x] = pixel[0] as f32 - 128.0;
+                }
+            }
+
+            let coefficients = dct_2d(&samples, n, n);
+            for v in 0..n {
+                for u in 0..n {
+                    let step = steps[v * n + u];
+                    let quantized = if step > 0.0 {
+                        (coefficients[v * n + u] * dct_scale(u, n) * dct_scale(v, n) / step).round()
+                    } else {
+                        0.0
+                    };
+                    let value = (quantized + COEFFICIENT_OFFSET).clamp(0.0, u16::MAX as f32) as u16;
+                    features.put_pixel(cell_x + u as u32, cell_y + v as u32, Luma([value]));
+                }
+            }
+        }
+    }
+    DynamicImage::ImageLuma16(features)
+}
+
+
+//-----------------------Helper Function for Debugging-----------------------//
+
+
+// Approximate the luminance of a block from its quantized coefficients
+pub fn convert_dct_to_normal(features: &DynamicImage, options: DctOptions) -> DynamicImage {
+    let steps = options.steps();
+    let n = DCT_CELL as usize;
+    let (width, height) = features.dimensions();
+    let features = features.to_luma16();
+
+    let mut normal_img = ImageBuffer::new(width, height);
+    for cell_y in (0..height).step_by(n) {
+        for cell_x in (0..width).step_by(n) {
+            let mut coefficients = vec![0f32; n * n];
+            for v in 0..n {
+                for u in 0..n {
+                    let (x, y) = (cell_x + u as u32, cell_y + v as u32);
+                    if x < width && y < height {
+                        coefficients[v * n + u] = (features.get_pixel(x, y)[0] as f32 - COEFFICIENT_OFFSET) * steps[v * n + u];
+                    }
+                }
+            }
+
+            let samples = idct_2d(&coefficients, n);
+            for y in 0..n {
+                for x in 0..n {
+                    let (px, py) = (cell_x + x as u32, cell_y + y as u32);
+                    if px < width && py < height {
+                        let value = (samples[y * n + x] + 128.0).round().clamp(0.0, 255.0) as u8;
+                        normal_img.put_pixel(px, py, Luma([value]));
+                    }
+                }
+            }
+        }
+    }
+    DynamicImage::ImageLuma8(normal_img)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::jpeg::JpegEncoder;
+
+    const OPTIONS: DctOptions = DctOptions { coefficients: 6, quantization: 1.0 };
+
+    // Smooth 64x48 luminance with some texture
+    fn gradient() -> GrayImage {
+        GrayImage::from_fn(64, 48, |x, y| Luma([(40.0 + 2.0 * x as f32 + 1.5 * y as f32 + 12.0 * ((x as f32 / 5.0).sin() * (y as f32 / 7.0).cos())) as u8]))
+    }
+
+    // Fraction of the kept coefficients that are equal in both features
+    fn matching_fraction(first: &DynamicImage, second: &DynamicImage) -> f32 {
+        let steps = OPTIONS.steps();
+        let (first, second) = (first.to_luma16(), second.to_luma16());
+        let kept: Vec<_> = first
+            .enumerate_pixels()
+            .filter(|(x, y, _)| steps[(y % DCT_CELL * DCT_CELL + x % DCT_CELL) as usize] > 0.0)
+            .map(|(x, y, pixel)| pixel == second.get_pixel(x, y))
+            .collect();
+        kept.iter().filter(|&&matching| matching).count() as f32 / kept.len() as f32
+    }
+
+    fn unchanged_pixels(first: &GrayImage, second: &GrayImage) -> f32 {
+        first.pixels().zip(second.pixels()).filter(|(a, b)| a == b).count() as f32 / first.pixels().len() as f32
+    }
+
+    #[test]
+    fn constant_cells_only_keep_their_dc_value() {
+        let features = dct_features(&GrayImage::from_pixel(8, 8, Luma([200])), OPTIONS).to_luma16();
+
+        // Orthonormal DC of the level shifted cell, 8 * 72, quantized by 16
+        assert_eq!(features.get_pixel(0, 0)[0], COEFFICIENT_OFFSET as u16 + 36);
+        assert!(features.pixels().skip(1).all(|pixel| pixel[0] == COEFFICIENT_OFFSET as u16));
+    }
+
+    #[test]
+    fn coefficients_past_the_zigzag_count_are_dropped() {
+        let cell = GrayImage::from_fn(8, 8, |x, y| Luma([((x * 53 + y * 97) % 256) as u8]));
+        let options = DctOptions { coefficients: 3, quantization: 1.0 };
+        let features = dct_features(&cell, options).to_luma16();
+        let full = dct_features(&cell, DctOptions { coefficients: 64, quantization: 1.0 }).to_luma16();
+
+        // The first three zig-zag positions are (0, 0), (1, 0) and (0, 1)
+        for (x, y, pixel) in features.enumerate_pixels() {
+            if [(0, 0), (1, 0), (0, 1)].contains(&(x, y)) {
+                assert_eq!(pixel, full.get_pixel(x, y));
+            } else {
+                assert_eq!(pixel[0], COEFFICIENT_OFFSET as u16, "coefficient ({}, {})", x, y);
+            }
+        }
+        assert!(full.pixels().filter(|pixel| pixel[0] != COEFFICIENT_OFFSET as u16).count() > 3);
+    }
+
+    #[test]
+    fn tiles_have_the_features_of_the_same_area() {
+        let img = gradient();
+        let features = dct_features(&img, OPTIONS);
+        let tile = image::imageops::crop_imm(&img, 16, 8, 32, 24).to_image();
+        assert_eq!(dct_features(&tile, OPTIONS).to_luma16(), features.crop_imm(16, 8, 32, 24).to_luma16());
+    }
+
+    #[test]
+    fn features_are_stable_under_mild_noise() {
+        let img = gradient();
+        let noisy = GrayImage::from_fn(64, 48, |x, y| {
+            let noise = [-1i16, 0, 1][((x * 7 + y * 13) % 3) as usize];
+            Luma([(img.get_pixel(x, y)[0] as i16 + noise).clamp(0, 255) as u8])
+        });
+        assert!(unchanged_pixels(&img, &noisy) < 0.4);
+        assert!(matching_fraction(&dct_features(&img, OPTIONS), &dct_features(&noisy, OPTIONS)) > 0.95);
+    }
+
+    #[test]
+    fn features_are_stable_under_jpeg_recompression() {
+        let img = gradient();
+        let mut encoded = Vec::new();
+        JpegEncoder::new_with_quality(&mut encoded, 90).encode(img.as_raw(), 64, 48, image::ColorType::L8).unwrap();
+        let recompressed = image::load_from_memory(&encoded).unwrap().to_luma8();
+
+        let unchanged = unchanged_pixels(&img, &recompressed);
+        assert!(matching_fraction(&dct_features(&img, OPTIONS), &dct_features(&recompressed, OPTIONS)) > unchanged.max(0.9));
+    }
+}
@@ -0,0 +1,280 @@
+// src/disclosure.rs
+
+use crate::block_encryption::{
+    block_mac_with_key, color_type_name, decode_block, decrypt_block_data_with_key, derive_block_key, derive_mac_key, parse_color_type, BlockEncryptionError,
+    MAC_KEY_LEN,
+};
+use crate::blockchain::Block;
+use crate::ipfs_upload::download_file_from_ipfs;
+use crate::key_store::{BlockKey, KEY_LEN};
+use crate::merkle_tree::{build_tree, verify_proof, InclusionProof};
+use crate::perceptual_hash::{make_leaf, split_leaf};
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum DisclosureError {
+    #[error("block is not a registration")]
+    NotARegistration,
+    #[error("block {0} is not part of the registration")]
+    UnknownBlock(u32),
+    #[error("bundle was issued for merkle root {bundle}, registration has {registered}")]
+    RootMismatch { bundle: String, registered: String },
+    #[error("merkle root {0} is not valid hex")]
+    InvalidRoot(String),
+    #[error("disclosed block {0} is malformed")]
+    MalformedBlock(u32),
+    #[error("inclusion proof of block {0} does not match the registered merkle root")]
+    InvalidProof(u32),
+    #[error("decrypted block {0} does not match its registered MAC")]
+    MacMismatch(u32),
+    #[error("failed to download block {index}: {source}")]
+    Download { index: u32, source: ipfs_api::Error },
+    #[error(transparent)]
+    Decrypt(#[from] BlockEncryptionError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Format(#[from] serde_json::Error),
+}
+
+// A single disclosed block: its IPFS hash, its block key and the sibling hashes proving that
+// its leaf belongs to the registered merkle tree. Binary values are hex encoded.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DisclosedBlock {
+    pub index: u32,
+    pub cid: String,
+    pub mac: String, // MAC of the block features, its leaf
+    pub mac_key: String, // Key checking the MAC of the decrypted block
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub perceptual_hash: Option<String>, // Set when the registration uses perceptual leaves
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<usize>, // Position of the leaf in quadtree-shaped merkle trees
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<(u32, u32)>, // Width and height of the block, when blocks are not uniform
+    pub block_key: String,
+    pub proof: Vec<String>,
+}
+
+// Everything a third party needs to decrypt the disclosed blocks of a registered image,
+// and nothing that would decrypt the other blocks
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DisclosureBundle {
+    pub image_id: String,
+    pub block_width: u32,
+    pub block_height: u32,
+    pub color_type: String,
+    pub merkle_root: String,
+    pub leaf_count: usize, // Number of leaves of the registered merkle tree
+    pub blocks: Vec<DisclosedBlock>,
+}
+
+impl DisclosureBundle {
+    pub fn save(&self, path: &Path) -> Result<(), DisclosureError> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, DisclosureError> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn block_dimensions(&self) -> (u32, u32) {
+        (self.block_width, self.block_height)
+    }
+}
+
+// Export the block keys, hashes and inclusion proofs of the selected blocks of a registration
+pub fn export_disclosure(registration: &Block, data_key: &BlockKey, image_id: &str, indices: &[u32]) -> Result<DisclosureBundle, DisclosureError> {
+    let transaction = registration.transaction.as_ref().ok_or(DisclosureError::NotARegistration)?;
+    let leaves = &transaction.tx;
+    let (block_width, block_height) = transaction.block_layout().dimensions();
+    let block_dimensions = transaction.block_dimensions();
+    let merkle_tree = build_tree(leaves.clone(), transaction.tree_shape().as_ref());
+
+    let mut blocks = Vec::with_capacity(indices.len());
+    for &index in indices {
+        let proof = merkle_tree.prove(index as usize).ok_or(DisclosureError::UnknownBlock(index))?;
+        let (perceptual_hash, leaf) = split_leaf(&proof.leaf);
+        let cid = transaction.block_cid(index as usize).ok_or(DisclosureError::UnknownBlock(index))?;
+        blocks.push(DisclosedBlock {
+            index,
+            cid: cid.to_string(),
+            mac: leaf.to_string(),
+            mac_key: hex::encode(derive_mac_key(data_key, image_id, index)),
+            perceptual_hash: perceptual_hash.map(|hash| format!("{:016x}", hash)),
+            position: proof.position,
+            dimensions: transaction.quadtree.as_ref().map(|_| block_dimensions[index as usize]),
+            block_key: hex::encode(derive_block_key(data_key, image_id, index)),
+            proof: proof.siblings,
+        });
+    }
+
+    Ok(DisclosureBundle {
+        image_id: image_id.to_string(),
+        block_width,
+        block_height,
+        color_type: color_type_name(transaction.block_color),
+        merkle_root: registration.header.merkle_root.clone(),
+        leaf_count: leaves.len(),
+        blocks,
+    })
+}
+
+// Check every disclosed block against the merkle root recorded on chain
+pub fn verify_disclosure(bundle: &DisclosureBundle, merkle_root: &str) -> Result<(), DisclosureError> {
+    if bundle.merkle_root != merkle_root {
+        return Err(DisclosureError::RootMismatch {
+            bundle: bundle.merkle_root.clone(),
+            registered: merkle_root.to_string(),
+        });
+    }
+    let root = hex::decode(merkle_root).map_err(|_| DisclosureError::InvalidRoot(merkle_root.to_string()))?;
+
+    for block in &bundle.blocks {
+        if block.proof.iter().any(|sibling| hex::decode(sibling).is_err()) {
+            return Err(DisclosureError::MalformedBlock(block.index));
+        }
+        let perceptual_hash = block
+            .perceptual_hash
+            .as_deref()
+            .map(|hash| u64::from_str_radix(hash, 16))
+            .transpose()
+            .map_err(|_| DisclosureError::MalformedBlock(block.index))?;
+
+        let leaf = make_leaf(perceptual_hash, &block.mac);
+        let proof = InclusionProof {
+            index: block.index as usize,
+            leaf_count: bundle.leaf_count,
+            position: block.position,
+            leaf: leaf.clone(),
+            siblings: block.proof.clone(),
+        };
+        if !verify_proof(&root, &leaf, block.index as usize, &proof) {
+            return Err(DisclosureError::InvalidProof(block.index));
+        }
+    }
+    Ok(())
+}
+
+// Download and decrypt the disclosed blocks once they are proven to belong to the registration
+pub async fn open_disclosure(bundle: &DisclosureBundle, merkle_root: &str) -> Result<Vec<(u32, DynamicImage)>, DisclosureError> {
+    verify_disclosure(bundle, merkle_root)?;
+
+    let mut opened = Vec::with_capacity(bundle.blocks.len());
+    for block in &bundle.blocks {
+        let encrypted_block = download_file_from_ipfs(&block.cid)
+            .await
+            .map_err(|source| DisclosureError::Download { index: block.index, source })?;
+        opened.push((block.index, open_disclosed_block(bundle, block, &encrypted_block)?));
+    }
+    Ok(opened)
+}
+
+// Decrypt a disclosed block of a verified bundle. Its IPFS hash is not part of the proof, so
+// the decrypted samples are checked against the proven MAC.
+pub fn open_disclosed_block(bundle: &DisclosureBundle, block: &DisclosedBlock, encrypted_block: &[u8]) -> Result<DynamicImage, DisclosureError> {
+    let color = parse_color_type(&bundle.color_type)?;
+    let block_key: [u8; KEY_LEN] = decode_key(&block.block_key).ok_or(DisclosureError::MalformedBlock(block.index))?;
+    let dimensions = block.dimensions.unwrap_or(bundle.block_dimensions());
+
+    let samples = decrypt_block_data_with_key(encrypted_block, &block_key, &bundle.image_id, block.index, dimensions)?;
+    let mac_key: [u8; MAC_KEY_LEN] = decode_key(&block.mac_key).ok_or(DisclosureError::MalformedBlock(block.index))?;
+    if block_mac_with_key(&samples, dimensions, &mac_key, &bundle.image_id, block.index) != block.mac {
+        return Err(DisclosureError::MacMismatch(block.index));
+    }
+    Ok(decode_block(samples, dimensions, color)?)
+}
+
+fn decode_key<const N: usize>(key: &str) -> Option<[u8; N]> {
+    hex::decode(key).ok().and_then(|key| key.try_into().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_encryption::{block_mac, encrypt_block_data};
+    use crate::blockchain::{test_registration, Blockchain};
+    use crate::image_to_chunks::{BlockGrid, BlockLayout, Padding};
+    use crate::merkle_tree::insert_root;
+
+    const LAYOUT: BlockLayout = BlockLayout { block_width: 4, block_height: 4, padding: Padding::Zero };
+
+    fn samples(index: u32) -> Vec<u8> {
+        vec![index as u8 * 10; 64]
+    }
+
+    // Registration of a row of five 4x4 RGBA blocks and their ciphertexts
+    fn registration(key: &BlockKey) -> (Blockchain, Vec<Vec<u8>>) {
+        let encrypted: Vec<Vec<u8>> = (0..5).map(|i| encrypt_block_data(&samples(i), (4, 4), key, "image", i)).collect();
+        let transaction = test_registration(
+            BlockGrid::new(20, 4, LAYOUT),
+            (0..5).map(|i| block_mac(&samples(i), (4, 4), key, "image", i)).collect(),
+            (0..5).map(|i| format!("cid {}", i)).collect(),
+        );
+        let mut blockchain = Blockchain::new();
+        insert_root(transaction, &mut blockchain);
+        (blockchain, encrypted)
+    }
+
+    fn key() -> BlockKey {
+        BlockKey::new("dek-test", [5; KEY_LEN]).unwrap()
+    }
+
+    #[test]
+    fn disclosed_blocks_round_trip() {
+        let (blockchain, encrypted) = registration(&key());
+        let block = blockchain.chain.last().unwrap();
+        let bundle = export_disclosure(block, &key(), "image", &[1, 4]).unwrap();
+        let bundle: DisclosureBundle = serde_json::from_str(&serde_json::to_string(&bundle).unwrap()).unwrap();
+
+        verify_disclosure(&bundle, &block.header.merkle_root).unwrap();
+        for disclosed in &bundle.blocks {
+            let opened = open_disclosed_block(&bundle, disclosed, &encrypted[disclosed.index as usize]).unwrap();
+            assert_eq!(opened.to_bytes(), samples(disclosed.index));
+        }
+        assert_eq!(bundle.blocks[1].cid, "cid 4");
+    }
+
+    #[test]
+    fn tampered_proofs_are_rejected() {
+        let (blockchain, _) = registration(&key());
+        let block = blockchain.chain.last().unwrap();
+        let root = &block.header.merkle_root;
+
+        let mut bundle = export_disclosure(block, &key(), "image", &[2]).unwrap();
+        bundle.blocks[0].proof[0] = hex::encode([0u8; 32]);
+        assert!(matches!(verify_disclosure(&bundle, root), Err(DisclosureError::InvalidProof(2))));
+
+        // A proof moved to another block, or claiming another MAC
+        let mut bundle = export_disclosure(block, &key(), "image", &[2]).unwrap();
+        bundle.blocks[0].index = 3;
+        assert!(matches!(verify_disclosure(&bundle, root), Err(DisclosureError::InvalidProof(3))));
+        let mut bundle = export_disclosure(block, &key(), "image", &[2]).unwrap();
+        bundle.blocks[0].mac = block_mac(&samples(3), (4, 4), &key(), "image", 2);
+        assert!(matches!(verify_disclosure(&bundle, root), Err(DisclosureError::InvalidProof(2))));
+
+        // A bundle issued for another registration
+        let bundle = export_disclosure(block, &key(), "image", &[2]).unwrap();
+        assert!(matches!(verify_disclosure(&bundle, "00"), Err(DisclosureError::RootMismatch { .. })));
+        assert!(matches!(export_disclosure(block, &key(), "image", &[5]), Err(DisclosureError::UnknownBlock(5))));
+    }
+
+    #[test]
+    fn substituted_ciphertexts_are_rejected() {
+        let (blockchain, encrypted) = registration(&key());
+        let block = blockchain.chain.last().unwrap();
+        let bundle = export_disclosure(block, &key(), "image", &[1]).unwrap();
+        let disclosed = &bundle.blocks[0];
+
+        // The ciphertext of another block does not decrypt under the disclosed block key
+        assert!(matches!(open_disclosed_block(&bundle, disclosed, &encrypted[2]), Err(DisclosureError::Decrypt(_))));
+
+        // Other samples encrypted under the data key decrypt, but do not match the proven MAC
+        let substituted = encrypt_block_data(&samples(2), (4, 4), &key(), "image", 1);
+        assert!(matches!(open_disclosed_block(&bundle, disclosed, &substituted), Err(DisclosureError::MacMismatch(1))));
+    }
+}
@@ -0,0 +1,220 @@
+// src/feature_extractor.rs
+
+use crate::canonicalize::load_canonical_image;
+use crate::dct_features::{convert_dct_to_normal, dct_features, extract_dct_features, DctOptions, DCT_CELL};
+use crate::image_to_msb::{convert_msb_to_normal, extract_msb, msb_features, LumaStandard, MsbOptions, Sample};
+use image::{imageops, DynamicImage, GrayImage, Luma};
+
+// Turns an image into the representation that is sliced into blocks, encrypted and hashed.
+// Features keep the geometry of the image so that block indices map to image areas.
+pub trait FeatureExtractor {
+    // Extractor and parameters, as recorded in the registration
+    fn spec(&self) -> ExtractorSpec;
+
+    fn extract(&self, img_path: &str) -> DynamicImage;
+
+    // Features of a tile of the canonical image, for extractors whose features only depend on
+    // the pixels of their own cell. Tiles must start on a cell boundary.
+    fn extract_tile(&self, _tile: &DynamicImage) -> Option<DynamicImage> {
+        None
+    }
+
+    // Whether `extract_tile` gives features, so that tiled images need not be decoded whole
+    fn supports_tiles(&self) -> bool {
+        false
+    }
+
+    // Approximate the original pixels of a block of features, for debugging
+    fn convert_to_normal(&self, features: &DynamicImage) -> DynamicImage;
+
+    // Blocks must be a multiple of this size so that no feature depends on two blocks
+    fn cell_size(&self) -> u32 {
+        1
+    }
+}
+
+// The feature extractor a registration was created with, so that verification uses the same one
+#[derive(Debug, Clone, Copy)]
+pub enum ExtractorSpec {
+    Msb(MsbOptions), // MSBs of the RGB channels, or of the luminance
+    Dct(DctOptions),
+    BlurredLuma(BlurredLumaOptions),
+    Raw,
+}
+
+impl ExtractorSpec {
+    pub fn extractor(&self) -> Box<dyn FeatureExtractor> {
+        match *self {
+            ExtractorSpec::Msb(options) => Box::new(options),
+            ExtractorSpec::Dct(options) => Box::new(options),
+            ExtractorSpec::BlurredLuma(options) => Box::new(options),
+            ExtractorSpec::Raw => Box::new(RawPixels),
+        }
+    }
+}
+
+impl FeatureExtractor for MsbOptions {
+    fn spec(&self) -> ExtractorSpec {
+        ExtractorSpec::Msb(*self)
+    }
+
+    fn extract(&self, img_path: &str) -> DynamicImage {
+        extract_msb(img_path, *self)
+    }
+
+    fn extract_tile(&self, tile: &DynamicImage) -> Option<DynamicImage> {
+        Some(msb_features(tile, *self))
+    }
+
+    fn supports_tiles(&self) -> bool {
+        true
+    }
+
+    fn convert_to_normal(&self, features: &DynamicImage) -> DynamicImage {
+        convert_msb_to_normal(features, *self)
+    }
+}
+
+impl FeatureExtractor for DctOptions {
+    fn spec(&self) -> ExtractorSpec {
+        ExtractorSpec::Dct(*self)
+    }
+
+    fn extract(&self, img_path: &str) -> DynamicImage {
+        extract_dct_features(img_path, *self)
+    }
+
+    fn extract_tile(&self, tile: &DynamicImage) -> Option<DynamicImage> {
+        Some(dct_features(&tile.to_luma8(), *self))
+    }
+
+    fn supports_tiles(&self) -> bool {
+        true
+    }
+
+    fn convert_to_normal(&self, features: &DynamicImage) -> DynamicImage {
+        convert_dct_to_normal(features, *self)
+    }
+
+    fn cell_size(&self) -> u32 {
+        DCT_CELL
+    }
+}
+
+// Blurred luminance reduced to its most significant bits: noise and compression artifacts
+// are smoothed out before the bit-planes are taken.
+// This only changes the features the leaves are computed from, which stay exact MACs. It is
+// unrelated to `LeafMode::Perceptual`, which prefixes the MAC of the features of any extractor
+// with a DCT perceptual hash and tolerates blocks within a Hamming distance; both can be combined.
+#[derive(Debug, Clone, Copy)]
+pub struct BlurredLumaOptions {
+    pub blur: f32, // Standard deviation of the Gaussian blur, in pixels
+    pub bits: u8,  // Number of most significant bits of the blurred luminance kept, from 1 to 8
+}
+
+impl BlurredLumaOptions {
+    fn msb_options(&self) -> MsbOptions {
+        MsbOptions { bits: self.bits, include_alpha: false, luma: Some(LumaStandard::Bt601) }
+    }
+
+    fn features(&self, luma: &GrayImage) -> DynamicImage {
+        let mask = u8::msb_mask(self.bits);
+        let mut features = imageops::blur(luma, self.blur);
+        for pixel in features.pixels_mut() {
+            *pixel = Luma([pixel[0] & mask]);
+        }
+        DynamicImage::ImageLuma8(features)
+    }
+}
+
+impl FeatureExtractor for BlurredLumaOptions {
+    fn spec(&self) -> ExtractorSpec {
+        ExtractorSpec::BlurredLuma(*self)
+    }
+
+    fn extract(&self, img_path: &str) -> DynamicImage {
+        self.features(&load_canonical_image(img_path).expect("Failed to open image").to_luma8())
+    }
+
+    fn convert_to_normal(&self, features: &DynamicImage) -> DynamicImage {
+        convert_msb_to_normal(features, self.msb_options())
+    }
+}
+
+// The pixels themselves, at their own depth and with transparency
+#[derive(Debug, Clone, Copy)]
+pub struct RawPixels;
+
+impl FeatureExtractor for RawPixels {
+    fn spec(&self) -> ExtractorSpec {
+        ExtractorSpec::Raw
+    }
+
+    fn extract(&self, img_path: &str) -> DynamicImage {
+        load_canonical_image(img_path).expect("Failed to open image")
+    }
+
+    fn extract_tile(&self, tile: &DynamicImage) -> Option<DynamicImage> {
+        Some(tile.clone())
+    }
+
+    fn supports_tiles(&self) -> bool {
+        true
+    }
+
+    fn convert_to_normal(&self, features: &DynamicImage) -> DynamicImage {
+        features.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    const BLURRED_LUMA: BlurredLumaOptions = BlurredLumaOptions { blur: 1.5, bits: 2 };
+
+    fn specs() -> Vec<ExtractorSpec> {
+        vec![
+            ExtractorSpec::Msb(MsbOptions { bits: 1, include_alpha: false, luma: None }),
+            ExtractorSpec::Dct(DctOptions { coefficients: 6, quantization: 1.0 }),
+            ExtractorSpec::BlurredLuma(BLURRED_LUMA),
+            ExtractorSpec::Raw,
+        ]
+    }
+
+    #[test]
+    fn only_extractors_supporting_tiles_extract_them() {
+        let tile = DynamicImage::ImageRgba8(RgbaImage::from_pixel(16, 16, Rgba([10, 200, 30, 255])));
+        for spec in specs() {
+            let extractor = spec.extractor();
+            assert_eq!(extractor.supports_tiles(), extractor.extract_tile(&tile).is_some(), "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn blurred_luma_keeps_the_msbs_of_the_luminance() {
+        let features = BLURRED_LUMA.features(&GrayImage::from_pixel(16, 16, Luma([200])));
+        assert_eq!(features.color(), image::ColorType::L8);
+        assert!(features.to_luma8().pixels().all(|pixel| pixel[0] == 0xC0));
+    }
+
+    #[test]
+    fn blurred_luma_smooths_out_isolated_noise() {
+        let flat = GrayImage::from_pixel(16, 16, Luma([150]));
+        let mut noisy = flat.clone();
+        noisy.put_pixel(8, 8, Luma([0]));
+
+        // The MSBs of the noisy pixel change, but not those of the blurred luminance
+        let msb = MsbOptions { bits: 2, include_alpha: false, luma: Some(LumaStandard::Bt601) };
+        assert_ne!(msb_features(&DynamicImage::ImageLuma8(noisy.clone()), msb).to_bytes(), msb_features(&DynamicImage::ImageLuma8(flat.clone()), msb).to_bytes());
+        assert_eq!(BLURRED_LUMA.features(&noisy).to_bytes(), BLURRED_LUMA.features(&flat).to_bytes());
+    }
+
+    #[test]
+    fn blurred_luma_is_converted_back_to_gray_levels() {
+        let features = BLURRED_LUMA.features(&GrayImage::from_pixel(8, 8, Luma([200])));
+        let normal = BLURRED_LUMA.convert_to_normal(&features).to_luma8();
+        assert!(normal.pixels().all(|pixel| (0xC0..=0xFF).contains(&pixel[0])));
+    }
+}
@@ -1,33 +1,338 @@
 extern crate image;
 
-use image::{ImageBuffer, Rgba};
+use crate::image_to_msb::is_16_bit;
+use crate::quadtree::QuadtreePartition;
+use image::{DynamicImage, GenericImageView, ImageBuffer, Pixel};
 
-pub fn slice_image_into_blocks(image: &ImageBuffer<Rgba<u8>, Vec<u8>>, block_size: u32) -> Vec<ImageBuffer<Rgba<u8>, Vec<u8>>> {
-    let (width, height) = image.dimensions();
-    let mut blocks = Vec::new();
-
-    for y in (0..height).step_by(block_size as usize) {
-        for x in (0..width).step_by(block_size as usize) {
-            let mut block = ImageBuffer::new(block_size, block_size);
-
-            for by in 0..block_size {
-                for bx in 0..block_size {
-                    if x + bx < width && y + by < height {
-                        let pixel = image.get_pixel(x + bx, y + by);
-                        block.put_pixel(bx, by, *pixel);
-                    }
+// How the pixels of partial edge blocks that fall outside the image are filled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+    Zero, // Transparent black
+    Edge, // Repeat the last row and column of the image
+}
+
+// Size of the blocks and padding of the partial edge blocks, chosen at registration.
+// Blocks need not be square, e.g. wide strips for panoramas and scanned documents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockLayout {
+    pub block_width: u32,
+    pub block_height: u32,
+    pub padding: Padding,
+}
+
+impl BlockLayout {
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.block_width, self.block_height)
+    }
+
+    // Offset of the shifted grid: half a block to the right and down
+    pub fn half_block(&self) -> (u32, u32) {
+        (self.block_width / 2, self.block_height / 2)
+    }
+}
+
+// Grid of blocks covering an image: partial blocks on the right and bottom edges are kept
+// and padded, so every pixel belongs to exactly one block. Blocks are numbered row by row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockGrid {
+    pub width: u32,
+    pub height: u32,
+    pub layout: BlockLayout,
+}
+
+impl BlockGrid {
+    pub fn new(width: u32, height: u32, layout: BlockLayout) -> Self {
+        assert!(layout.block_width > 0 && layout.block_height > 0, "block dimensions must be positive, got {:?}", layout.dimensions());
+        BlockGrid { width, height, layout }
+    }
+
+    pub fn columns(&self) -> u32 {
+        self.width.div_ceil(self.layout.block_width)
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.height.div_ceil(self.layout.block_height)
+    }
+
+    pub fn block_count(&self) -> usize {
+        (self.columns() * self.rows()) as usize
+    }
+
+    // Top-left pixel of a block
+    pub fn block_origin(&self, index: u32) -> (u32, u32) {
+        ((index % self.columns()) * self.layout.block_width, (index / self.columns()) * self.layout.block_height)
+    }
+
+    // Whether two grids number the same areas of an image identically
+    pub fn is_aligned_with(&self, other: &BlockGrid) -> bool {
+        self.layout.dimensions() == other.layout.dimensions() && self.columns() == other.columns() && self.rows() == other.rows()
+    }
+}
+
+// Area of the image covered by a block. Blocks on the right and bottom edges may extend
+// past the image: their outside pixels are padded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRegion {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl BlockRegion {
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn overlaps(&self, other: &BlockRegion) -> bool {
+        self.x < other.x + other.width && other.x < self.x + self.width && self.y < other.y + other.height && other.y < self.y + self.height
+    }
+}
+
+// How an image is divided into blocks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockPartition {
+    Grid(BlockLayout),
+    // Blocks of the layout subdivided where the content is detailed
+    Quadtree(QuadtreePartition),
+    // Blocks of the layout on a grid offset by half a block, whose boundaries run through the
+    // middle of the grid blocks. The top and left half blocks of the image are not covered.
+    Shifted(BlockLayout),
+}
+
+impl BlockPartition {
+    // Size of the blocks of the grid, or of the roots of the quadtrees
+    pub fn layout(&self) -> BlockLayout {
+        match self {
+            BlockPartition::Grid(layout) | BlockPartition::Shifted(layout) => *layout,
+            BlockPartition::Quadtree(partition) => partition.roots.layout,
+        }
+    }
+
+    // Regions of the blocks of a `width` x `height` image, in leaf order
+    pub fn regions(&self, width: u32, height: u32) -> Vec<BlockRegion> {
+        match self {
+            BlockPartition::Grid(layout) => BlockGrid::new(width, height, *layout).regions(),
+            BlockPartition::Quadtree(partition) => partition.leaves(),
+            BlockPartition::Shifted(layout) => {
+                let (dx, dy) = layout.half_block();
+                if width <= dx || height <= dy {
+                    return Vec::new();
                 }
+                BlockGrid::new(width - dx, height - dy, *layout)
+                    .regions()
+                    .into_iter()
+                    .map(|region| BlockRegion { x: region.x + dx, y: region.y + dy, ..region })
+                    .collect()
+            }
+        }
+    }
+}
+
+impl BlockGrid {
+    pub fn regions(&self) -> Vec<BlockRegion> {
+        (0..self.block_count() as u32)
+            .map(|index| {
+                let (x, y) = self.block_origin(index);
+                BlockRegion { x, y, width: self.layout.block_width, height: self.layout.block_height }
+            })
+            .collect()
+    }
+}
+
+// A block of an image, borrowed from it rather than copied. Blocks are only copied, one at a
+// time, when they are read, so that very large images are never held twice in memory.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockView<'a> {
+    image: &'a DynamicImage,
+    pub region: BlockRegion,
+    padding: Padding,
+}
+
+impl BlockView<'_> {
+    // Copy the block into its own image of the same color type, so luma images give single-channel
+    // blocks, with the pixels outside the image padded
+    pub fn to_image(self) -> DynamicImage {
+        let (region, padding) = (self.region, self.padding);
+        match self.image {
+            DynamicImage::ImageLuma8(img) => DynamicImage::ImageLuma8(crop_block(img, region, padding)),
+            DynamicImage::ImageLumaA8(img) => DynamicImage::ImageLumaA8(crop_block(img, region, padding)),
+            DynamicImage::ImageRgba8(img) => DynamicImage::ImageRgba8(crop_block(img, region, padding)),
+            DynamicImage::ImageLuma16(img) => DynamicImage::ImageLuma16(crop_block(img, region, padding)),
+            DynamicImage::ImageLumaA16(img) => DynamicImage::ImageLumaA16(crop_block(img, region, padding)),
+            DynamicImage::ImageRgba16(img) => DynamicImage::ImageRgba16(crop_block(img, region, padding)),
+            other => {
+                // Convert the part of the block inside the image only, then pad it
+                let width = region.width.min(other.width().saturating_sub(region.x));
+                let height = region.height.min(other.height().saturating_sub(region.y));
+                let inside = other.crop_imm(region.x, region.y, width, height).to_rgba8();
+                DynamicImage::ImageRgba8(crop_block(&inside, BlockRegion { x: 0, y: 0, ..region }, padding))
             }
+        }
+    }
+}
+
+// Views of the blocks of an image, in leaf order
+pub fn block_views<'a>(image: &'a DynamicImage, partition: &BlockPartition) -> impl Iterator<Item = BlockView<'a>> + 'a {
+    let padding = partition.layout().padding;
+    partition
+        .regions(image.width(), image.height())
+        .into_iter()
+        .map(move |region| BlockView { image, region, padding })
+}
+
+// Copy a region of an image row by row, padding the pixels outside the image
+fn crop_block<P: Pixel + 'static>(image: &ImageBuffer<P, Vec<P::Subpixel>>, region: BlockRegion, padding: Padding) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let (width, height) = image.dimensions();
+    let mut block = ImageBuffer::new(region.width, region.height);
+    if width == 0 || height == 0 {
+        return block;
+    }
 
-            blocks.push(block);
+    let channels = P::CHANNEL_COUNT as usize;
+    let source = image.as_raw();
+    let block_row_len = region.width as usize * channels;
+    // Columns of the block inside the image
+    let inside = region.width.min(width.saturating_sub(region.x)) as usize * channels;
+
+    for (by, row) in block.chunks_exact_mut(block_row_len).enumerate() {
+        let y = region.y + by as u32;
+        if y >= height && padding == Padding::Zero {
+            break;
+        }
+
+        let source_start = (y.min(height - 1) as usize * width as usize + region.x.min(width - 1) as usize) * channels;
+        if inside > 0 {
+            row[..inside].copy_from_slice(&source[source_start..source_start + inside]);
+        }
+
+        if padding == Padding::Edge {
+            // Repeat the last pixel of the source row over the columns outside the image
+            let last = (y.min(height - 1) as usize * width as usize + width as usize - 1) * channels;
+            for pixel in row[inside..].chunks_exact_mut(channels) {
+                pixel.copy_from_slice(&source[last..last + channels]);
+            }
         }
     }
+    block
+}
 
-    blocks
+pub fn save_block(block: &DynamicImage, prefix: &str, index: usize) {
+    // JPEG has no 16-bit samples
+    let extension = if is_16_bit(block) { "png" } else { "jpg" };
+    block.save(format!("{}{}.{}", prefix, index, extension)).unwrap();
 }
 
-pub fn save_blocks(blocks:Vec<ImageBuffer<Rgba<u8>, Vec<u8>>>,prefix:&str){
-    for (i,block) in blocks.iter().enumerate(){
-        block.save(format!("{}{}.jpg",prefix,i)).unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+
+    // 70x45 image whose pixels encode their position
+    fn odd_image() -> GrayImage {
+        ImageBuffer::from_fn(70, 45, |x, y| Luma([(x + y * 70) as u8 | 1]))
+    }
+
+    fn slice(image: &GrayImage, layout: BlockLayout) -> Vec<GrayImage> {
+        let image = DynamicImage::ImageLuma8(image.clone());
+        block_views(&image, &BlockPartition::Grid(layout)).map(|view| view.to_image().to_luma8()).collect()
+    }
+
+    #[test]
+    fn grid_keeps_partial_edge_blocks() {
+        let grid = BlockGrid::new(70, 45, BlockLayout { block_width: 32, block_height: 32, padding: Padding::Zero });
+        assert_eq!((grid.columns(), grid.rows()), (3, 2));
+        assert_eq!(grid.block_count(), 6);
+        assert_eq!(grid.block_origin(2), (64, 0));
+        assert_eq!(grid.block_origin(3), (0, 32));
+        assert_eq!(grid.block_origin(5), (64, 32));
+    }
+
+    #[test]
+    fn slicing_follows_the_grid() {
+        let image = odd_image();
+        let layout = BlockLayout { block_width: 32, block_height: 32, padding: Padding::Zero };
+        let grid = BlockGrid::new(image.width(), image.height(), layout);
+        let blocks = slice(&image, layout);
+
+        assert_eq!(blocks.len(), grid.block_count());
+        for (index, block) in blocks.iter().enumerate() {
+            let (x, y) = grid.block_origin(index as u32);
+            assert_eq!((block.width(), block.height()), (32, 32));
+            assert_eq!(block.get_pixel(0, 0), image.get_pixel(x, y));
+        }
+    }
+
+    #[test]
+    fn zero_padding_fills_outside_pixels_with_zeros() {
+        let image = odd_image();
+        let blocks = slice(&image, BlockLayout { block_width: 32, block_height: 32, padding: Padding::Zero });
+
+        // Bottom right block covers pixels 64..70 x 32..45
+        let corner = &blocks[5];
+        assert_eq!(corner.get_pixel(5, 12), image.get_pixel(69, 44));
+        assert_eq!(corner.get_pixel(6, 0)[0], 0);
+        assert_eq!(corner.get_pixel(0, 13)[0], 0);
+        assert_eq!(corner.get_pixel(31, 31)[0], 0);
+    }
+
+    #[test]
+    fn edge_padding_repeats_the_last_row_and_column() {
+        let image = odd_image();
+        let blocks = slice(&image, BlockLayout { block_width: 32, block_height: 32, padding: Padding::Edge });
+
+        let corner = &blocks[5];
+        assert_eq!(corner.get_pixel(6, 0), image.get_pixel(69, 32));
+        assert_eq!(corner.get_pixel(0, 13), image.get_pixel(64, 44));
+        assert_eq!(corner.get_pixel(31, 31), image.get_pixel(69, 44));
+    }
+
+    #[test]
+    fn dynamic_images_keep_their_color_type() {
+        let image = DynamicImage::ImageLuma16(ImageBuffer::from_pixel(33, 17, Luma([1000u16])));
+        let partition = BlockPartition::Grid(BlockLayout { block_width: 16, block_height: 16, padding: Padding::Edge });
+        let blocks: Vec<DynamicImage> = block_views(&image, &partition).map(|view| view.to_image()).collect();
+
+        assert_eq!(blocks.len(), 3 * 2);
+        assert!(blocks.iter().all(|block| block.color() == image::ColorType::L16));
+        assert_eq!(blocks[5].to_luma16().get_pixel(15, 15)[0], 1000);
+    }
+
+    #[test]
+    fn rectangular_blocks_are_numbered_row_by_row() {
+        let image = odd_image();
+        let layout = BlockLayout { block_width: 64, block_height: 8, padding: Padding::Zero };
+        let grid = BlockGrid::new(image.width(), image.height(), layout);
+        assert_eq!((grid.columns(), grid.rows()), (2, 6));
+        assert_eq!(grid.block_origin(3), (64, 8));
+
+        let blocks = slice(&image, layout);
+        assert_eq!(blocks.len(), 12);
+        assert_eq!(blocks[3].dimensions(), (64, 8));
+        assert_eq!(blocks[3].get_pixel(5, 7), image.get_pixel(69, 15));
+        assert_eq!(blocks[11].get_pixel(5, 4), image.get_pixel(69, 44));
+        assert_eq!(blocks[11].get_pixel(5, 5)[0], 0);
+    }
+
+    #[test]
+    fn other_color_types_are_padded_as_rgba() {
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_fn(20, 10, |x, y| image::Rgb([x as u8, y as u8, 7])));
+        let partition = BlockPartition::Grid(BlockLayout { block_width: 16, block_height: 16, padding: Padding::Edge });
+        let blocks: Vec<DynamicImage> = block_views(&image, &partition).map(|view| view.to_image()).collect();
+
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks.iter().all(|block| block.color() == image::ColorType::Rgba8));
+        assert_eq!(blocks[1].to_rgba8().get_pixel(2, 3).0, [18, 3, 7, 255]);
+        assert_eq!(blocks[1].to_rgba8().get_pixel(15, 15).0, [19, 9, 7, 255]);
+    }
+
+    #[test]
+    fn grids_of_different_sizes_are_not_aligned() {
+        let layout = BlockLayout { block_width: 32, block_height: 32, padding: Padding::Zero };
+        let grid = BlockGrid::new(70, 45, layout);
+        assert!(grid.is_aligned_with(&BlockGrid::new(90, 60, layout)));
+        assert!(!grid.is_aligned_with(&BlockGrid::new(100, 45, layout)));
+        assert!(!grid.is_aligned_with(&BlockGrid::new(70, 45, BlockLayout { block_width: 16, ..layout })));
+        assert!(!grid.is_aligned_with(&BlockGrid::new(70, 45, BlockLayout { block_width: 16, block_height: 64, ..layout })));
     }
 }
@@ -1,33 +1,182 @@
 extern crate image;
 
-use image::{GenericImageView, ImageBuffer, Rgba};
+use crate::canonicalize::load_canonical_image;
+use image::{DynamicImage, ImageBuffer, Luma, LumaA, Pixel, Primitive, Rgba};
+use rayon::prelude::*;
+use std::ops::{BitAnd, BitOr};
+use thiserror::Error;
 
-pub fn extract_msb(img_path: &str) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
-    // Load the image from a file
-    let img = image::open(img_path).expect("Failed to open image");
+#[derive(Debug, Error)]
+pub enum MsbError {
+    #[error("unknown luma standard {0}, expected bt601, bt709 or rgb")]
+    UnknownLumaStandard(String),
+}
 
-    // Get the dimensions of the image
-    let (width, height) = img.dimensions();
+// Weights used to compute luminance from RGB
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LumaStandard {
+    Bt601,
+    Bt709,
+}
 
-    // Create a new image buffer to store the MSB extracted image
-    let mut msb_img = ImageBuffer::new(width, height);
+impl LumaStandard {
+    // Parse a configured standard, or "rgb" to keep the RGB channels
+    pub fn from_name(name: &str) -> Result<Option<Self>, MsbError> {
+        match name.to_ascii_lowercase().as_str() {
+            "bt601" => Ok(Some(LumaStandard::Bt601)),
+            "bt709" => Ok(Some(LumaStandard::Bt709)),
+            "rgb" => Ok(None),
+            _ => Err(MsbError::UnknownLumaStandard(name.to_string())),
+        }
+    }
+
+    pub fn luma<S: Sample>(self, r: S, g: S, b: S) -> S {
+        let (kr, kg, kb) = match self {
+            LumaStandard::Bt601 => (0.299, 0.587, 0.114),
+            LumaStandard::Bt709 => (0.2126, 0.7152, 0.0722),
+        };
+        S::from_f32(kr * r.to_f32() + kg * g.to_f32() + kb * b.to_f32())
+    }
+}
+
+// Parameters of the MSB representation that is authenticated
+#[derive(Debug, Clone, Copy)]
+pub struct MsbOptions {
+    pub bits: u8,                   // Number of most significant bits kept per channel, from 1 to 16
+    pub include_alpha: bool,        // Authenticate transparency too, instead of forcing it opaque
+    pub luma: Option<LumaStandard>, // Authenticate luminance only, producing single-channel blocks
+}
+
+// Channel depths the MSB representation is defined for
+pub trait Sample: Primitive + BitAnd<Output = Self> + BitOr<Output = Self> + Send + Sync + 'static {
+    const DEPTH: u8;
+    const MAX: Self;
+
+    // Mask keeping the `k` most significant bits of a channel
+    fn msb_mask(k: u8) -> Self;
+
+    // Midpoint of the range of the discarded low bits (e.g. 64 for k = 1 on 8 bits, 0 for k = 8)
+    fn midpoint(k: u8) -> Self;
+
+    fn to_f32(self) -> f32;
+    fn from_f32(value: f32) -> Self;
+}
+
+macro_rules! impl_sample {
+    ($t:ty, $bits:expr) => {
+        impl Sample for $t {
+            const DEPTH: u8 = $bits;
+            const MAX: Self = <$t>::MAX;
+
+            fn msb_mask(k: u8) -> Self {
+                assert!((1..=Self::DEPTH).contains(&k), "k must be between 1 and {}, got {}", Self::DEPTH, k);
+                <$t>::MAX << (Self::DEPTH - k)
+            }
+
+            fn midpoint(k: u8) -> Self {
+                (!Self::msb_mask(k)).wrapping_add(1) >> 1
+            }
 
-    // Iterate over each pixel
-    for (x, y, pixel) in img.pixels() {
-        // Extract the color channels (assuming the image is in RGBA format)
-        let rgba = pixel.0;
+            fn to_f32(self) -> f32 {
+                self as f32
+            }
 
-        // Extract the MSB of each color channel
-        let r_msb = (rgba[0] >> 7) & 0x01;
-        let g_msb = (rgba[1] >> 7) & 0x01;
-        let b_msb = (rgba[2] >> 7) & 0x01;
+            fn from_f32(value: f32) -> Self {
+                value.round() as $t
+            }
+        }
+    };
+}
+
+impl_sample!(u8, 8);
+impl_sample!(u16, 16);
+
+// Number of bits kept for a channel of type `S`: shallower channels are kept whole
+fn kept_bits<S: Sample>(options: MsbOptions) -> u8 {
+    assert!((1..=16).contains(&options.bits), "k must be between 1 and 16, got {}", options.bits);
+    options.bits.min(S::DEPTH)
+}
+
+// Whether an image has 16 bits per channel
+pub fn is_16_bit(img: &DynamicImage) -> bool {
+    let color = img.color();
+    color.bytes_per_pixel() > color.channel_count()
+}
+
+pub fn extract_msb(img_path: &str, options: MsbOptions) -> DynamicImage {
+    // Load the image from a file, in sRGB and upright
+    let img = load_canonical_image(img_path).expect("Failed to open image");
+    msb_features(&img, options)
+}
+
+// MSB representation of a canonical image, or of a tile of one: each pixel only depends on itself
+pub fn msb_features(img: &DynamicImage, options: MsbOptions) -> DynamicImage {
+    // 16-bit images are authenticated on their own bit-planes instead of being down-converted
+    if is_16_bit(img) {
+        let rgba = img.to_rgba16();
+        match options.luma {
+            None => DynamicImage::ImageRgba16(extract_msb_rgba(&rgba, options)),
+            Some(standard) if options.include_alpha => DynamicImage::ImageLumaA16(extract_msb_luma_alpha(&rgba, standard, options)),
+            Some(standard) => DynamicImage::ImageLuma16(extract_msb_luma(&rgba, standard, options)),
+        }
+    } else {
+        let rgba = img.to_rgba8();
+        match options.luma {
+            None => DynamicImage::ImageRgba8(extract_msb_rgba(&rgba, options)),
+            Some(standard) if options.include_alpha => DynamicImage::ImageLumaA8(extract_msb_luma_alpha(&rgba, standard, options)),
+            Some(standard) => DynamicImage::ImageLuma8(extract_msb_luma(&rgba, standard, options)),
+        }
+    }
+}
+
+fn extract_msb_rgba<S: Sample>(img: &ImageBuffer<Rgba<S>, Vec<S>>, options: MsbOptions) -> ImageBuffer<Rgba<S>, Vec<S>> {
+    let mask = S::msb_mask(kept_bits::<S>(options));
+
+    map_pixels(img, |rgba| {
+        // Keep the k MSBs of each color channel in place, clearing the lower bits
+        let r_msb = rgba[0] & mask;
+        let g_msb = rgba[1] & mask;
+        let b_msb = rgba[2] & mask;
+        let a_msb = if options.include_alpha { rgba[3] & mask } else { S::MAX };
+
+        // Create a new pixel with the MSBs
+        Rgba([r_msb, g_msb, b_msb, a_msb])
+    })
+}
+
+fn extract_msb_luma_alpha<S: Sample>(img: &ImageBuffer<Rgba<S>, Vec<S>>, standard: LumaStandard, options: MsbOptions) -> ImageBuffer<LumaA<S>, Vec<S>> {
+    let mask = S::msb_mask(kept_bits::<S>(options));
+    map_pixels(img, |[r, g, b, a]| LumaA([standard.luma(r, g, b) & mask, a & mask]))
+}
 
-        // Create a new pixel with the MSBs (scaled up to 255 for visibility)
-        let msb_pixel = Rgba([r_msb * 255, g_msb * 255, b_msb * 255, 255]);
+// Only luminance is kept, so a recolouring that preserves it is not reported
+fn extract_msb_luma<S: Sample>(img: &ImageBuffer<Rgba<S>, Vec<S>>, standard: LumaStandard, options: MsbOptions) -> ImageBuffer<Luma<S>, Vec<S>> {
+    let mask = S::msb_mask(kept_bits::<S>(options));
+    map_pixels(img, |[r, g, b, _]| Luma([standard.luma(r, g, b) & mask]))
+}
 
-        // Put the new pixel into the MSB image buffer
-        msb_img.put_pixel(x, y, msb_pixel);
+// Map each pixel of an RGBA image to a new pixel, processing rows in parallel
+fn map_pixels<S, P, F>(img: &ImageBuffer<Rgba<S>, Vec<S>>, map: F) -> ImageBuffer<P, Vec<S>>
+where
+    S: Sample,
+    P: Pixel<Subpixel = S> + 'static,
+    F: Fn([S; 4]) -> P + Sync,
+{
+    let (width, height) = img.dimensions();
+    let mut msb_img = ImageBuffer::new(width, height);
+    if width == 0 || height == 0 {
+        return msb_img;
     }
+
+    let channels = P::CHANNEL_COUNT as usize;
+    msb_img
+        .par_chunks_mut(width as usize * channels)
+        .zip(img.par_chunks(width as usize * 4))
+        .for_each(|(msb_row, row)| {
+            for (msb_pixel, pixel) in msb_row.chunks_exact_mut(channels).zip(row.chunks_exact(4)) {
+                msb_pixel.copy_from_slice(map([pixel[0], pixel[1], pixel[2], pixel[3]]).channels());
+            }
+        });
     msb_img
 }
 
@@ -35,29 +184,136 @@ pub fn extract_msb(img_path: &str) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
 //-----------------------Helper Function for Debugging-----------------------//
 
 
-pub fn convert_msb_to_normal(msb_img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
-    // Get the dimensions of the MSB image
-    let (width, height) = msb_img.dimensions();
+pub fn convert_msb_to_normal(msb_img: &DynamicImage, options: MsbOptions) -> DynamicImage {
+    match msb_img {
+        DynamicImage::ImageLuma8(img) => DynamicImage::ImageLuma8(restore_msb(img, options)),
+        DynamicImage::ImageLumaA8(img) => DynamicImage::ImageLumaA8(restore_msb(img, options)),
+        DynamicImage::ImageLuma16(img) => DynamicImage::ImageLuma16(restore_msb(img, options)),
+        DynamicImage::ImageLumaA16(img) => DynamicImage::ImageLumaA16(restore_msb(img, options)),
+        DynamicImage::ImageRgba16(img) => DynamicImage::ImageRgba16(restore_msb(img, options)),
+        other => DynamicImage::ImageRgba8(restore_msb(&other.to_rgba8(), options)),
+    }
+}
+
+fn restore_msb<P, S>(msb_img: &ImageBuffer<P, Vec<S>>, options: MsbOptions) -> ImageBuffer<P, Vec<S>>
+where
+    P: Pixel<Subpixel = S> + 'static,
+    S: Sample,
+{
+    let bits = kept_bits::<S>(options);
+    let mask = S::msb_mask(bits);
+
+    // The discarded low bits are unknown: assume the midpoint of their range
+    let midpoint = S::midpoint(bits);
 
-    // Create a new image buffer to store the approximated normal image
-    let mut normal_img = ImageBuffer::new(width, height);
+    // Reconstruct the original color channels from the MSBs
+    let mut normal_img = msb_img.clone();
+    for pixel in normal_img.pixels_mut() {
+        pixel.apply_with_alpha(
+            |value| (value & mask) | midpoint,
+            |alpha| if options.include_alpha { (alpha & mask) | midpoint } else { S::MAX },
+        );
+    }
+    normal_img
+}
 
-    // Iterate over each pixel
-    for (x, y, pixel) in msb_img.enumerate_pixels() {
-        // Extract the MSB values (assuming the MSB image is in RGBA format)
-        let rgba = pixel.0;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_encryption::{block_mac, block_samples};
+    use crate::key_store::{BlockKey, KEY_LEN};
+    use image::{ColorType, ImageBuffer, Luma};
 
-        // Reconstruct the original color channels using the MSB
-        // Here, we assume a middle value for the lower 7 bits (e.g., 64)
-        let r = if rgba[0] == 255 { 128 + 64 } else { 64 };
-        let g = if rgba[1] == 255 { 128 + 64 } else { 64 };
-        let b = if rgba[2] == 255 { 128 + 64 } else { 64 };
+    fn options(bits: u8, include_alpha: bool) -> MsbOptions {
+        MsbOptions { bits, include_alpha, luma: None }
+    }
 
-        // Create a new pixel with the reconstructed values
-        let normal_pixel = Rgba([r, g, b, 255]);
+    fn luma_options(bits: u8, include_alpha: bool, standard: LumaStandard) -> MsbOptions {
+        MsbOptions { bits, include_alpha, luma: Some(standard) }
+    }
 
-        // Put the new pixel into the normal image buffer
-        normal_img.put_pixel(x, y, normal_pixel);
+    fn uniform(pixel: Rgba<u8>) -> DynamicImage {
+        DynamicImage::ImageRgba8(ImageBuffer::from_pixel(4, 4, pixel))
     }
-    normal_img
-}
\ No newline at end of file
+
+    // Leaf of the features of a whole image, as a single block
+    fn leaf(image: &DynamicImage, options: MsbOptions) -> String {
+        let features = msb_features(image, options);
+        let key = BlockKey::new("dek-test", [6; KEY_LEN]).unwrap();
+        block_mac(&block_samples(&features), (4, 4), &key, "image", 0)
+    }
+
+    #[test]
+    fn luma_standards_are_parsed_by_name() {
+        assert_eq!(LumaStandard::from_name("BT601").unwrap(), Some(LumaStandard::Bt601));
+        assert_eq!(LumaStandard::from_name("bt709").unwrap(), Some(LumaStandard::Bt709));
+        assert_eq!(LumaStandard::from_name("rgb").unwrap(), None);
+        assert!(matches!(LumaStandard::from_name("bt907"), Err(MsbError::UnknownLumaStandard(name)) if name == "bt907"));
+    }
+
+    #[test]
+    fn luminance_features_have_a_single_channel() {
+        let rgba8 = uniform(Rgba([10, 200, 30, 128]));
+        let rgba16 = DynamicImage::ImageRgba16(ImageBuffer::from_pixel(4, 4, Rgba([1000u16, 50000, 3000, 40000])));
+        let standard = LumaStandard::Bt709;
+        assert_eq!(msb_features(&rgba8, luma_options(2, false, standard)).color(), ColorType::L8);
+        assert_eq!(msb_features(&rgba8, luma_options(2, true, standard)).color(), ColorType::La8);
+        assert_eq!(msb_features(&rgba16, luma_options(2, false, standard)).color(), ColorType::L16);
+        assert_eq!(msb_features(&rgba16, luma_options(2, true, standard)).color(), ColorType::La16);
+    }
+
+    #[test]
+    fn luma_standards_weigh_the_channels_differently() {
+        // Pure red is 0.299 * 255 = 76 in BT.601 and 0.2126 * 255 = 54 in BT.709
+        let red = uniform(Rgba([255, 0, 0, 255]));
+        let bt601 = msb_features(&red, luma_options(8, false, LumaStandard::Bt601)).to_luma8();
+        let bt709 = msb_features(&red, luma_options(8, false, LumaStandard::Bt709)).to_luma8();
+        assert_eq!(bt601.get_pixel(0, 0)[0], 76);
+        assert_eq!(bt709.get_pixel(0, 0)[0], 54);
+    }
+
+    #[test]
+    fn recolourings_keeping_the_luminance_keep_the_leaves() {
+        // Gray 100 and (0, 150, 105) both have a BT.601 luminance of 100
+        let gray = uniform(Rgba([100, 100, 100, 255]));
+        let recoloured = uniform(Rgba([0, 150, 105, 255]));
+        let brighter = uniform(Rgba([160, 160, 160, 255]));
+        let luma = luma_options(4, false, LumaStandard::Bt601);
+
+        assert_eq!(leaf(&gray, luma), leaf(&recoloured, luma));
+        assert_ne!(leaf(&gray, luma), leaf(&brighter, luma));
+
+        // Authenticating the RGB channels reports the recolouring
+        assert_ne!(leaf(&gray, options(4, false)), leaf(&recoloured, options(4, false)));
+    }
+
+    #[test]
+    fn midpoints_are_half_the_discarded_range() {
+        assert_eq!(<u8 as Sample>::midpoint(1), 64);
+        assert_eq!(<u8 as Sample>::midpoint(3), 16);
+        assert_eq!(<u8 as Sample>::midpoint(8), 0);
+        assert_eq!(<u16 as Sample>::midpoint(1), 0x4000);
+        assert_eq!(<u16 as Sample>::midpoint(16), 0);
+    }
+
+    #[test]
+    fn restored_channels_are_at_the_midpoint_of_their_msbs() {
+        let pixel = Rgba([0b1010_1111, 0b0001_0000, 0xFF, 0x37]);
+        let msb = msb_features(&DynamicImage::ImageRgba8(ImageBuffer::from_pixel(2, 2, pixel)), options(3, true));
+        let restored = convert_msb_to_normal(&msb, options(3, true)).to_rgba8();
+        assert_eq!(*restored.get_pixel(1, 1), Rgba([0b1011_0000, 0b0001_0000, 0b1111_0000, 0b0011_0000]));
+
+        // Alpha is opaque when it is not authenticated, and all bits kept are restored exactly
+        let restored = convert_msb_to_normal(&msb, options(3, false)).to_rgba8();
+        assert_eq!(restored.get_pixel(0, 0)[3], 0xFF);
+        let exact = convert_msb_to_normal(&msb_features(&DynamicImage::ImageRgba8(ImageBuffer::from_pixel(1, 1, pixel)), options(8, true)), options(8, true));
+        assert_eq!(*exact.to_rgba8().get_pixel(0, 0), pixel);
+    }
+
+    #[test]
+    fn sixteen_bit_samples_are_restored_at_their_own_depth() {
+        let msb = DynamicImage::ImageLuma16(ImageBuffer::from_pixel(1, 1, Luma([0xABCDu16])));
+        let DynamicImage::ImageLuma16(restored) = convert_msb_to_normal(&msb, options(4, false)) else { panic!("restored image is not 16-bit gray") };
+        assert_eq!(restored.get_pixel(0, 0)[0], 0xA800);
+    }
+}
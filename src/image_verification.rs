@@ -1,8 +1,68 @@
-
 use crate::merkle_tree::{MerkleTree, compare_merkle_trees};
+use crate::perceptual_hash::{hamming_distance, split_leaf, LeafMode};
 
 pub fn image_verification(fake_merkle_tree: MerkleTree, original_merkle_tree: MerkleTree) -> Vec<u32>{
     let ri = compare_merkle_trees(&original_merkle_tree, &fake_merkle_tree);
     println!("Tampered result array: {:?} {:?}", ri,ri.len());
     ri
 }
+
+// In perceptual mode, clear the blocks whose perceptual hashes are within the Hamming
+// threshold: their MSBs changed, but not in a way that alters what the block shows
+pub fn ignore_perceptual_changes(ri: &mut [u32], original_leaves: &[String], fake_leaves: &[String], leaf_mode: LeafMode) {
+    let LeafMode::Perceptual { max_distance } = leaf_mode else {
+        return;
+    };
+
+    for (i, r) in ri.iter_mut().enumerate() {
+        let hashes = original_leaves.get(i).zip(fake_leaves.get(i)).map(|(original, fake)| (split_leaf(original).0, split_leaf(fake).0));
+        if let Some((Some(original_hash), Some(fake_hash))) = hashes {
+            if *r == 1 && hamming_distance(original_hash, fake_hash) <= max_distance {
+                *r = 0;
+            }
+        }
+    }
+    println!("Tampered result array after perceptual matching: {:?} {:?}", ri, ri.len());
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::perceptual_hash::make_leaf;
+
+    fn leaves(hashes: &[u64], macs: &[&str]) -> Vec<String> {
+        hashes.iter().zip(macs).map(|(&hash, mac)| make_leaf(Some(hash), mac)).collect()
+    }
+
+    #[test]
+    fn changes_within_the_threshold_are_ignored() {
+        let original = leaves(&[0, 0, 0], &["a", "b", "c"]);
+        // Blocks 0 and 1 changed by 3 and 4 bits, block 2 by 5 bits
+        let fake = leaves(&[0b111, 0b1111, 0b11111], &["x", "y", "z"]);
+
+        let mut ri = vec![1, 1, 1];
+        ignore_perceptual_changes(&mut ri, &original, &fake, LeafMode::Perceptual { max_distance: 4 });
+        assert_eq!(ri, [0, 0, 1]);
+    }
+
+    #[test]
+    fn exact_mode_reports_every_change() {
+        let original = leaves(&[0, 0], &["a", "b"]);
+        let fake = leaves(&[0, 1], &["x", "y"]);
+
+        let mut ri = vec![1, 1];
+        ignore_perceptual_changes(&mut ri, &original, &fake, LeafMode::Exact);
+        assert_eq!(ri, [1, 1]);
+    }
+
+    #[test]
+    fn leaves_without_perceptual_hashes_are_reported() {
+        let original = vec!["a".to_string(), make_leaf(Some(0), "b")];
+        let fake = vec![make_leaf(Some(0), "x"), "y".to_string()];
+
+        let mut ri = vec![1, 1];
+        ignore_perceptual_changes(&mut ri, &original, &fake, LeafMode::Perceptual { max_distance: 64 });
+        assert_eq!(ri, [1, 1]);
+    }
+}
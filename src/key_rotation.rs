@@ -0,0 +1,297 @@
+// src/key_rotation.rs
+
+use crate::block_encryption::{block_mac, decrypt_block_data, encrypt_block_data, save_to_file, BlockEncryptionError};
+use crate::blockchain::{calculate_hash, Blockchain, Transaction};
+use crate::ipfs_upload::{download_file_from_ipfs, upload_to_ipfs};
+use crate::key_sharing::{KeyProtection, KeyShare, KeySharingError};
+use crate::key_store::{generate_data_key, BlockKey, KeyStore, KeyStoreError};
+use crate::merkle_tree::build_tree;
+use crate::perceptual_hash::{make_leaf, split_leaf};
+use crate::pyramid::{level_image_id, PyramidLevel};
+use crate::shifted_grid::{shifted_image_id, ShiftedGrid};
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum KeyRotationError {
+    #[error("no registration block with hash {0}")]
+    UnknownRegistration(String),
+    #[error("registration {0} has no blocks to rotate")]
+    EmptyRegistration(String),
+    #[error("registration has no IPFS hash for block {0}")]
+    MissingBlock(usize),
+    #[error(transparent)]
+    KeyStore(#[from] KeyStoreError),
+    #[error(transparent)]
+    KeySharing(#[from] KeySharingError),
+    #[error("failed to download block {index}: {source}")]
+    Download { index: usize, source: ipfs_api::Error },
+    #[error("failed to decrypt block {index}: {source}")]
+    Decrypt { index: usize, source: BlockEncryptionError },
+    #[error("failed to upload block {index}: {source}")]
+    Upload { index: usize, source: ipfs_api::Error },
+}
+
+// Outcome of a key rotation
+#[derive(Debug)]
+pub struct RotatedRegistration {
+    pub block_hash: String,
+    pub key_shares: Vec<KeyShare>, // New custodian shares when the data key is split
+}
+
+// Re-encrypt every block of a registration under a fresh data key.
+// Each block recorded in the registration is downloaded from IPFS, decrypted with the key
+// named in its header, encrypted with the new data key and uploaded again. The new hashes
+// are recorded in a new block that supersedes the old registration.
+//
+// The new data key is protected like the old one: wrapped by the same master key, which must be
+// in `key_store`, or split again between the same number of custodians. A data key held by
+// custodians must already have been reconstructed into `key_store`.
+pub async fn rotate_block_keys(
+    blockchain: &mut Blockchain,
+    block_hash: &str,
+    key_store: &mut KeyStore,
+    image_id: &str,
+    prefix: &str,
+) -> Result<RotatedRegistration, KeyRotationError> {
+    let registration = blockchain
+        .find_block(block_hash)
+        .and_then(|block| block.transaction.clone())
+        .ok_or_else(|| KeyRotationError::UnknownRegistration(block_hash.to_string()))?;
+    let block_dimensions = registration.block_dimensions();
+    let layout = registration.block_layout();
+
+    // Make the current data key of the image available for decryption
+    if let KeyProtection::Wrapped(wrapped_key) = &registration.key_protection {
+        key_store.unwrap_data_key(wrapped_key, image_id)?;
+    }
+
+    let new_key = generate_data_key();
+
+    let leaves = rotate_leaves(&registration.tx, &registration.cids, &block_dimensions, key_store, &new_key, image_id, prefix).await?;
+
+    // Pyramid levels are encrypted under their own image id and rotated with the same data key
+    let mut pyramid = Vec::with_capacity(registration.pyramid.len());
+    for level in &registration.pyramid {
+        let layout = level.grid.layout;
+        let level_prefix = format!("{}_level{}x{}", prefix, layout.block_width, layout.block_height);
+        let dimensions = vec![layout.dimensions(); level.leaves.len()];
+        let (leaves, cids) = rotate_leaves(&level.leaves, &level.cids, &dimensions, key_store, &new_key, &level_image_id(image_id, layout), &level_prefix).await?;
+        pyramid.push(PyramidLevel { grid: level.grid, leaves, cids });
+    }
+
+    // So are the blocks of the shifted grid
+    let shifted = match &registration.shifted {
+        Some(old_shifted) => {
+            let dimensions = vec![layout.dimensions(); old_shifted.leaves.len()];
+            let (leaves, cids) = rotate_leaves(&old_shifted.leaves, &old_shifted.cids, &dimensions, key_store, &new_key, &shifted_image_id(image_id, layout), &format!("{}_shifted", prefix)).await?;
+            Some(ShiftedGrid { leaves, cids })
+        }
+        None => None,
+    };
+
+    let (key_protection, key_shares) = registration.key_protection.protect(&new_key, key_store, image_id)?;
+    let transaction = rotated_transaction(registration, block_hash, key_protection, leaves, pyramid, shifted);
+    let merkle_root = build_tree(transaction.tx.clone(), transaction.tree_shape().as_ref())
+        .root_hex()
+        .ok_or_else(|| KeyRotationError::EmptyRegistration(block_hash.to_string()))?;
+    blockchain.add_block(merkle_root, transaction);
+
+    let new_block = blockchain.chain.last().unwrap();
+    Ok(RotatedRegistration {
+        block_hash: calculate_hash(&new_block.header),
+        key_shares,
+    })
+}
+
+// Registration superseding `registration` with the rotated leaves and IPFS hashes of its blocks.
+// The partition, extractor and everything else describing the image are kept.
+fn rotated_transaction(
+    registration: Transaction,
+    block_hash: &str,
+    key_protection: KeyProtection,
+    (tx, cids): (Vec<String>, Vec<String>),
+    pyramid: Vec<PyramidLevel>,
+    shifted: Option<ShiftedGrid>,
+) -> Transaction {
+    Transaction {
+        tx,
+        cids,
+        supersedes: Some(block_hash.to_string()),
+        key_protection,
+        pyramid,
+        shifted,
+        ..registration
+    }
+}
+
+// Re-encrypt a block under `new_key` and compute its new leaf.
+// Blocks are re-encrypted as raw samples, whatever their color type. Perceptual hashes describe
+// the block, not its encryption, and are kept as they are.
+fn rotate_block(
+    encrypted_block: &[u8],
+    leaf: &str,
+    dimensions: (u32, u32),
+    key_store: &KeyStore,
+    new_key: &BlockKey,
+    image_id: &str,
+    block_index: u32,
+) -> Result<(Vec<u8>, String), BlockEncryptionError> {
+    let (perceptual_hash, _) = split_leaf(leaf);
+    let block = decrypt_block_data(encrypted_block, key_store, image_id, block_index, dimensions)?;
+    let reencrypted_block = encrypt_block_data(&block, dimensions, new_key, image_id, block_index);
+    let mac = block_mac(&block, dimensions, new_key, image_id, block_index);
+    Ok((reencrypted_block, make_leaf(perceptual_hash, &mac)))
+}
+
+// Download, re-encrypt under `new_key` and upload again each block of a list of leaves.
+// Returns the leaves under the new key and the IPFS hashes of the re-encrypted blocks.
+async fn rotate_leaves(
+    leaves: &[String],
+    cids: &[String],
+    block_dimensions: &[(u32, u32)],
+    key_store: &KeyStore,
+    new_key: &BlockKey,
+    image_id: &str,
+    prefix: &str,
+) -> Result<(Vec<String>, Vec<String>), KeyRotationError> {
+    let mut new_leaves = Vec::with_capacity(leaves.len());
+    let mut new_cids = Vec::with_capacity(leaves.len());
+    for ((i, leaf), &dimensions) in leaves.iter().enumerate().zip(block_dimensions) {
+        let tx_hash = cids.get(i).ok_or_else(|| KeyRotationError::MissingBlock(i))?;
+        let encrypted_block = download_file_from_ipfs(tx_hash)
+            .await
+            .map_err(|source| KeyRotationError::Download { index: i, source })?;
+        let (reencrypted_block, new_leaf) = rotate_block(&encrypted_block, leaf, dimensions, key_store, new_key, image_id, i as u32)
+            .map_err(|source| KeyRotationError::Decrypt { index: i, source })?;
+
+        // Save the re-encrypted block and upload it in place of the old one
+        let file_name = format!("{}_block_{}.enc", prefix, i + 1);
+        let file_path = Path::new(&file_name);
+        save_to_file(&reencrypted_block, file_path);
+
+        let hash = upload_to_ipfs(file_path)
+            .await
+            .map_err(|source| KeyRotationError::Upload { index: i, source })?;
+        println!("Rotated block {} from {} to {}", i + 1, tx_hash, hash);
+        new_leaves.push(new_leaf);
+        new_cids.push(hash);
+    }
+    Ok((new_leaves, new_cids))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_encryption::{derive_block_key, decrypt_block_data_with_key};
+    use crate::blockchain::{test_registration, Blockchain};
+    use crate::image_to_chunks::{BlockGrid, BlockLayout, Padding};
+    use crate::key_store::{wrap_data_key, KEY_LEN};
+    use crate::perceptual_hash::LeafMode;
+    use crate::quadtree::{QuadtreeOptions, QuadtreePartition};
+    use image::{GrayImage, Luma};
+
+    const DIMENSIONS: (u32, u32) = (2, 2);
+    const LAYOUT: BlockLayout = BlockLayout { block_width: 8, block_height: 8, padding: Padding::Edge };
+
+    // A block encrypted under `key` and its leaf, prefixed with `perceptual_hash`
+    fn sealed_block(samples: &[u8], key: &BlockKey, index: u32, perceptual_hash: Option<u64>) -> (Vec<u8>, String) {
+        let encrypted_block = encrypt_block_data(samples, DIMENSIONS, key, "image", index);
+        let mac = block_mac(samples, DIMENSIONS, key, "image", index);
+        (encrypted_block, make_leaf(perceptual_hash, &mac))
+    }
+
+    fn rotate(encrypted_block: &[u8], leaf: &str, old_key: &BlockKey, new_key: &BlockKey, index: u32) -> (Vec<u8>, String) {
+        let mut key_store = KeyStore::new();
+        key_store.insert(old_key.clone());
+        rotate_block(encrypted_block, leaf, DIMENSIONS, &key_store, new_key, "image", index).unwrap()
+    }
+
+    fn leaves(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn rotated_blocks_open_under_the_new_key_only() {
+        let (old_key, new_key) = (generate_data_key(), generate_data_key());
+        let samples = [1u8; 16];
+        let (encrypted_block, leaf) = sealed_block(&samples, &old_key, 3, None);
+        let (rotated_block, _) = rotate(&encrypted_block, &leaf, &old_key, &new_key, 3);
+
+        let mut key_store = KeyStore::new();
+        key_store.insert(new_key.clone());
+        assert_eq!(decrypt_block_data(&rotated_block, &key_store, "image", 3, DIMENSIONS).unwrap(), samples);
+
+        // The block names the new key, and the block key of the old one does not open it
+        let mut old_store = KeyStore::new();
+        old_store.insert(old_key.clone());
+        assert!(matches!(
+            decrypt_block_data(&rotated_block, &old_store, "image", 3, DIMENSIONS),
+            Err(BlockEncryptionError::KeyStore(KeyStoreError::UnknownKey(_)))
+        ));
+        let old_block_key = derive_block_key(&old_key, "image", 3);
+        assert!(matches!(
+            decrypt_block_data_with_key(&rotated_block, &old_block_key, "image", 3, DIMENSIONS),
+            Err(BlockEncryptionError::AuthenticationFailed(3))
+        ));
+    }
+
+    #[test]
+    fn rotated_leaves_keep_their_perceptual_hash() {
+        let (old_key, new_key) = (generate_data_key(), generate_data_key());
+        let samples = [7u8; 16];
+        let (encrypted_block, leaf) = sealed_block(&samples, &old_key, 0, Some(0xdead_beef));
+        let (_, rotated_leaf) = rotate(&encrypted_block, &leaf, &old_key, &new_key, 0);
+
+        assert_ne!(rotated_leaf, leaf);
+        assert_eq!(split_leaf(&rotated_leaf).0, Some(0xdead_beef));
+        assert_eq!(split_leaf(&rotated_leaf).1, block_mac(&samples, DIMENSIONS, &new_key, "image", 0));
+
+        // Exact leaves stay without a perceptual hash
+        let (encrypted_block, leaf) = sealed_block(&samples, &old_key, 0, None);
+        let (_, rotated_leaf) = rotate(&encrypted_block, &leaf, &old_key, &new_key, 0);
+        assert_eq!(split_leaf(&rotated_leaf).0, None);
+    }
+
+    #[test]
+    fn rotated_registrations_keep_the_partition_and_commit_the_new_leaves() {
+        let grid = BlockGrid::new(16, 16, LAYOUT);
+        let mut luma = GrayImage::new(16, 16);
+        luma.put_pixel(3, 3, Luma([255]));
+        let quadtree = QuadtreePartition::build(&luma, LAYOUT, QuadtreeOptions { min_block_size: 4, max_deviation: 1.0 });
+        let level_grid = BlockGrid::new(16, 16, BlockLayout { block_width: 16, block_height: 16, padding: Padding::Edge });
+        let registration = Transaction {
+            quadtree: Some(quadtree.clone()),
+            pyramid: vec![PyramidLevel { grid: level_grid, leaves: leaves(&["p"]), cids: leaves(&["8"]) }],
+            shifted: Some(ShiftedGrid { leaves: leaves(&["s"]), cids: leaves(&["9"]) }),
+            ..test_registration(grid, leaves(&["a", "b", "c", "d", "e", "f", "g"]), leaves(&["1", "2", "3", "4", "5", "6", "7"]))
+        };
+        let mut blockchain = Blockchain::new();
+        blockchain.add_block("old root".to_string(), registration.clone());
+        let old_header = blockchain.chain.last().unwrap().header.clone();
+
+        let master_key = BlockKey::new("department", [1; KEY_LEN]).unwrap();
+        let new_protection = KeyProtection::Wrapped(wrap_data_key(&generate_data_key(), &master_key, "image"));
+        let rotated = rotated_transaction(
+            registration,
+            "old hash",
+            new_protection,
+            (leaves(&["A", "B", "C", "D", "E", "F", "G"]), leaves(&["11", "12", "13", "14", "15", "16", "17"])),
+            vec![PyramidLevel { grid: level_grid, leaves: leaves(&["P"]), cids: leaves(&["18"]) }],
+            Some(ShiftedGrid { leaves: leaves(&["S"]), cids: leaves(&["19"]) }),
+        );
+        assert_eq!(rotated.supersedes.as_deref(), Some("old hash"));
+        assert_eq!(rotated.grid, grid);
+        assert_eq!(rotated.quadtree, Some(quadtree));
+        assert_eq!(rotated.leaf_mode, LeafMode::Exact);
+        assert_eq!(rotated.pyramid[0].grid, level_grid);
+        assert_eq!(rotated.block_cid(6), Some("17"));
+
+        blockchain.add_block("new root".to_string(), rotated.clone());
+        let header = &blockchain.chain.last().unwrap().header;
+        assert_eq!(header.pyramid_roots, [build_tree(rotated.pyramid[0].leaves.clone(), None).root_hex().unwrap()]);
+        assert_eq!(header.shifted_root, build_tree(rotated.shifted.unwrap().leaves, None).root_hex());
+        assert_ne!(header.pyramid_roots, old_header.pyramid_roots);
+        assert_ne!(header.shifted_root, old_header.shifted_root);
+    }
+}
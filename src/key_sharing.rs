@@ -0,0 +1,231 @@
+// src/key_sharing.rs
+
+use crate::key_store::{data_key_id, wrap_data_key, BlockKey, KeyStore, KeyStoreError, WrappedKey, KEY_LEN};
+use sharks::{Share, Sharks};
+use std::collections::HashSet;
+use std::fmt::{self, Debug, Formatter};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum KeySharingError {
+    #[error("threshold {threshold} must be between 2 and the number of custodians ({custodians})")]
+    InvalidThreshold { threshold: u8, custodians: u8 },
+    #[error("{provided} distinct shares of data key {data_key_id} provided, {threshold} required")]
+    NotEnoughShares { data_key_id: String, provided: usize, threshold: u8 },
+    #[error("shares do not reconstruct data key {0}")]
+    WrongKey(String),
+    #[error(transparent)]
+    KeyStore(#[from] KeyStoreError),
+}
+
+// Threshold policy of a data key split between custodians, stored in the registration
+// instead of a wrapped key so that no single party can decrypt the original blocks
+#[derive(Debug, Clone)]
+pub struct KeySharing {
+    pub data_key_id: String,
+    pub threshold: u8,
+    pub custodians: u8,
+}
+
+// How the data key of an image is protected, recorded in its registration: wrapped by the
+// master key of the registering department, or split between custodians
+#[derive(Debug, Clone)]
+pub enum KeyProtection {
+    Wrapped(WrappedKey),
+    Shared(KeySharing),
+}
+
+impl KeyProtection {
+    // Protect `data_key` like this protection protected the previous one: wrapped by the same
+    // master key, taken from `key_store`, or split again between as many custodians.
+    // Returns the new protection and the shares to hand to the custodians.
+    pub fn protect(&self, data_key: &BlockKey, key_store: &KeyStore, image_id: &str) -> Result<(KeyProtection, Vec<KeyShare>), KeySharingError> {
+        match self {
+            KeyProtection::Wrapped(wrapped) => {
+                let master_key = key_store.get(&wrapped.master_key_id)?;
+                Ok((KeyProtection::Wrapped(wrap_data_key(data_key, master_key, image_id)), Vec::new()))
+            }
+            KeyProtection::Shared(sharing) => {
+                let (sharing, shares) = split_data_key(data_key, sharing.threshold, sharing.custodians)?;
+                Ok((KeyProtection::Shared(sharing), shares))
+            }
+        }
+    }
+
+    // Recover the data key, unwrapped with the master key in `key_store` or combined from the
+    // shares of the approving custodians, and make it available in `key_store` for decryption
+    pub fn recover(&self, key_store: &mut KeyStore, image_id: &str, shares: &[KeyShare]) -> Result<BlockKey, KeySharingError> {
+        let key = match self {
+            KeyProtection::Wrapped(wrapped) => key_store.unwrap_data_key(wrapped, image_id)?.clone(),
+            KeyProtection::Shared(sharing) => combine_key_shares(sharing, shares)?,
+        };
+        key_store.insert(key.clone());
+        Ok(key)
+    }
+}
+
+// The share of a data key handed to one custodian
+#[derive(Clone)]
+pub struct KeyShare {
+    pub data_key_id: String,
+    share: Share,
+}
+
+// Never print share material
+impl Debug for KeyShare {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "KeyShare({}, #{})", self.data_key_id, self.share.x.0)
+    }
+}
+
+// Split a data key into one Shamir share per custodian, any `threshold` of which reconstruct it
+pub fn split_data_key(data_key: &BlockKey, threshold: u8, custodians: u8) -> Result<(KeySharing, Vec<KeyShare>), KeySharingError> {
+    if threshold < 2 || threshold > custodians {
+        return Err(KeySharingError::InvalidThreshold { threshold, custodians });
+    }
+
+    let shares = Sharks(threshold)
+        .dealer(data_key.bytes())
+        .take(custodians as usize)
+        .map(|share| KeyShare { data_key_id: data_key.id().to_string(), share })
+        .collect();
+
+    let sharing = KeySharing {
+        data_key_id: data_key.id().to_string(),
+        threshold,
+        custodians,
+    };
+    Ok((sharing, shares))
+}
+
+// Reconstruct a data key from the shares of the approving custodians.
+// The key id is a fingerprint of the key, so forged or mismatched shares are rejected.
+pub fn combine_key_shares(sharing: &KeySharing, shares: &[KeyShare]) -> Result<BlockKey, KeySharingError> {
+    // A share handed in twice would cancel its own Lagrange term, so only the first share of
+    // each custodian is kept
+    let mut custodians = HashSet::new();
+    let shares: Vec<&Share> = shares
+        .iter()
+        .filter(|share| share.data_key_id == sharing.data_key_id)
+        .map(|share| &share.share)
+        .filter(|share| custodians.insert(share.x.0))
+        .collect();
+
+    if shares.len() < sharing.threshold as usize {
+        return Err(KeySharingError::NotEnoughShares {
+            data_key_id: sharing.data_key_id.clone(),
+            provided: shares.len(),
+            threshold: sharing.threshold,
+        });
+    }
+
+    let wrong_key = || KeySharingError::WrongKey(sharing.data_key_id.clone());
+    let secret = Sharks(sharing.threshold).recover(shares).map_err(|_| wrong_key())?;
+    let key: [u8; KEY_LEN] = secret.try_into().map_err(|_| wrong_key())?;
+    if data_key_id(&key) != sharing.data_key_id {
+        return Err(wrong_key());
+    }
+
+    Ok(BlockKey::new(&sharing.data_key_id, key)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_store::{generate_data_key, KeyStore};
+
+    #[test]
+    fn threshold_shares_reconstruct_the_key() {
+        let data_key = generate_data_key();
+        let (sharing, shares) = split_data_key(&data_key, 3, 5).unwrap();
+
+        for approving in [&shares[..3], &shares[2..], &[shares[4].clone(), shares[0].clone(), shares[2].clone()][..]] {
+            let key = combine_key_shares(&sharing, approving).unwrap();
+            assert_eq!(key.bytes(), data_key.bytes());
+        }
+    }
+
+    #[test]
+    fn too_few_shares_are_rejected() {
+        let (sharing, shares) = split_data_key(&generate_data_key(), 3, 5).unwrap();
+        assert!(matches!(combine_key_shares(&sharing, &shares[..2]), Err(KeySharingError::NotEnoughShares { provided: 2, .. })));
+
+        // A share handed in twice counts once
+        let duplicated = [shares[0].clone(), shares[1].clone(), shares[1].clone()];
+        assert!(matches!(combine_key_shares(&sharing, &duplicated), Err(KeySharingError::NotEnoughShares { provided: 2, .. })));
+    }
+
+    #[test]
+    fn duplicate_shares_do_not_prevent_reconstruction() {
+        let data_key = generate_data_key();
+        let (sharing, shares) = split_data_key(&data_key, 3, 5).unwrap();
+
+        let duplicated = [shares[0].clone(), shares[1].clone(), shares[0].clone(), shares[3].clone()];
+        assert_eq!(combine_key_shares(&sharing, &duplicated).unwrap().bytes(), data_key.bytes());
+    }
+
+    #[test]
+    fn shares_of_another_key_are_rejected() {
+        let (sharing, shares) = split_data_key(&generate_data_key(), 3, 5).unwrap();
+        let (_, other_shares) = split_data_key(&generate_data_key(), 3, 5).unwrap();
+
+        // Shares of another data key are not counted
+        let mixed = [shares[0].clone(), shares[1].clone(), other_shares[2].clone()];
+        assert!(matches!(combine_key_shares(&sharing, &mixed), Err(KeySharingError::NotEnoughShares { provided: 2, .. })));
+
+        // Nor do they reconstruct this key when relabelled with its id
+        let relabelled = KeyShare { data_key_id: sharing.data_key_id.clone(), share: other_shares[2].share.clone() };
+        let forged = [shares[0].clone(), shares[1].clone(), relabelled];
+        assert!(matches!(combine_key_shares(&sharing, &forged), Err(KeySharingError::WrongKey(_))));
+    }
+
+    fn master_key_store() -> KeyStore {
+        let mut key_store = KeyStore::new();
+        key_store.insert(BlockKey::new("department", [9; KEY_LEN]).unwrap());
+        key_store
+    }
+
+    #[test]
+    fn wrapped_data_keys_are_recovered_with_the_master_key() {
+        let data_key = generate_data_key();
+        let key_store = master_key_store();
+        let protection = KeyProtection::Wrapped(wrap_data_key(&data_key, key_store.get("department").unwrap(), "image"));
+
+        let mut verifier_store = master_key_store();
+        assert_eq!(protection.recover(&mut verifier_store, "image", &[]).unwrap().bytes(), data_key.bytes());
+        assert!(verifier_store.get(data_key.id()).is_ok());
+
+        // Without the master key, the data key stays wrapped
+        assert!(protection.recover(&mut KeyStore::new(), "image", &[]).is_err());
+    }
+
+    #[test]
+    fn shared_data_keys_are_recovered_from_the_custodian_shares() {
+        let data_key = generate_data_key();
+        let (sharing, shares) = split_data_key(&data_key, 2, 3).unwrap();
+        let protection = KeyProtection::Shared(sharing);
+
+        // The master key alone does not recover a shared key
+        assert!(protection.recover(&mut master_key_store(), "image", &shares[..1]).is_err());
+        assert_eq!(protection.recover(&mut master_key_store(), "image", &shares[1..]).unwrap().bytes(), data_key.bytes());
+    }
+
+    #[test]
+    fn new_data_keys_are_protected_like_the_previous_one() {
+        let key_store = master_key_store();
+        let old_key = generate_data_key();
+        let new_key = generate_data_key();
+
+        let wrapped = KeyProtection::Wrapped(wrap_data_key(&old_key, key_store.get("department").unwrap(), "image"));
+        let (protection, shares) = wrapped.protect(&new_key, &key_store, "image").unwrap();
+        assert!(shares.is_empty());
+        assert!(matches!(&protection, KeyProtection::Wrapped(wrapped) if wrapped.master_key_id == "department" && wrapped.data_key_id == new_key.id()));
+        assert_eq!(protection.recover(&mut master_key_store(), "image", &[]).unwrap().bytes(), new_key.bytes());
+
+        let (sharing, _) = split_data_key(&old_key, 3, 5).unwrap();
+        let (protection, shares) = KeyProtection::Shared(sharing).protect(&new_key, &key_store, "image").unwrap();
+        assert!(matches!(&protection, KeyProtection::Shared(sharing) if sharing.threshold == 3 && sharing.custodians == 5));
+        assert_eq!(shares.len(), 5);
+        assert_eq!(protection.recover(&mut KeyStore::new(), "image", &shares[2..]).unwrap().bytes(), new_key.bytes());
+    }
+}
@@ -0,0 +1,307 @@
+// src/key_store.rs
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes128Gcm, Nonce};
+use argon2::Argon2;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+// Size of an AES-128 block key
+pub const KEY_LEN: usize = 16;
+
+// Size of the salt used for passphrase derivation
+pub const SALT_LEN: usize = 16;
+
+// Size of the random nonce used when wrapping a data key
+pub const WRAP_NONCE_LEN: usize = 12;
+
+#[derive(Debug, Error)]
+pub enum KeyStoreError {
+    #[error("unknown key id: {0}")]
+    UnknownKey(String),
+    #[error("key id must be between 1 and 255 bytes: {0:?}")]
+    InvalidKeyId(String),
+    #[error("{path} must hold {expected} bytes, found {actual}")]
+    InvalidLength { path: PathBuf, expected: usize, actual: usize },
+    #[error("failed to unwrap the data key of image {0}")]
+    UnwrapFailed(String),
+    #[error("wrapped key does not hold data key {0}")]
+    WrongDataKey(String),
+    #[error("failed to derive key from passphrase: {0}")]
+    Derivation(argon2::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+// A block encryption key together with the id stored in every ciphertext it produces
+#[derive(Clone)]
+pub struct BlockKey {
+    id: String,
+    key: [u8; KEY_LEN],
+}
+
+impl BlockKey {
+    pub fn new(id: &str, key: [u8; KEY_LEN]) -> Result<Self, KeyStoreError> {
+        if id.is_empty() || id.len() > u8::MAX as usize {
+            return Err(KeyStoreError::InvalidKeyId(id.to_string()));
+        }
+        Ok(BlockKey { id: id.to_string(), key })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn bytes(&self) -> &[u8; KEY_LEN] {
+        &self.key
+    }
+}
+
+// Never print key material
+impl Debug for BlockKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "BlockKey({})", self.id)
+    }
+}
+
+// A per-image data encryption key, wrapped by a master key with AES-128-GCM.
+// Stored in the registration record next to the merkle root.
+#[derive(Debug, Clone)]
+pub struct WrappedKey {
+    pub master_key_id: String,
+    pub data_key_id: String,
+    pub nonce: [u8; WRAP_NONCE_LEN],
+    pub ciphertext: Vec<u8>,
+}
+
+// Keys available to the application, indexed by key id.
+// Each department registers its images under its own key id.
+#[derive(Debug, Default)]
+pub struct KeyStore {
+    keys: HashMap<String, BlockKey>,
+}
+
+impl KeyStore {
+    pub fn new() -> Self {
+        KeyStore { keys: HashMap::new() }
+    }
+
+    pub fn insert(&mut self, key: BlockKey) -> &BlockKey {
+        let id = key.id.clone();
+        self.keys.insert(id.clone(), key);
+        &self.keys[&id]
+    }
+
+    // Derive a key from a passphrase with Argon2id. The salt is read from `salt_path`,
+    // or generated and stored there the first time the key is derived.
+    pub fn add_passphrase_key(&mut self, key_id: &str, passphrase: &str, salt_path: &Path) -> Result<&BlockKey, KeyStoreError> {
+        let salt = load_or_create_salt(salt_path)?;
+        let key = derive_key(passphrase, &salt)?;
+        Ok(self.insert(BlockKey::new(key_id, key)?))
+    }
+
+    // Load a raw 16 byte key from a key file
+    pub fn load_key_file(&mut self, key_id: &str, key_path: &Path) -> Result<&BlockKey, KeyStoreError> {
+        let key = read_fixed::<KEY_LEN>(key_path)?;
+        Ok(self.insert(BlockKey::new(key_id, key)?))
+    }
+
+    pub fn get(&self, key_id: &str) -> Result<&BlockKey, KeyStoreError> {
+        self.keys.get(key_id).ok_or_else(|| KeyStoreError::UnknownKey(key_id.to_string()))
+    }
+
+    // Unwrap the data key of an image with its master key and make it available for decryption.
+    // The key id is a fingerprint of the key, so a wrapped key relabelled with another id is rejected.
+    pub fn unwrap_data_key(&mut self, wrapped: &WrappedKey, image_id: &str) -> Result<&BlockKey, KeyStoreError> {
+        let master_key = self.get(&wrapped.master_key_id)?;
+
+        let cipher = Aes128Gcm::new(master_key.bytes().into());
+        let key = cipher
+            .decrypt(Nonce::from_slice(&wrapped.nonce), Payload { msg: &wrapped.ciphertext, aad: image_id.as_bytes() })
+            .map_err(|_| KeyStoreError::UnwrapFailed(image_id.to_string()))?;
+        let key: [u8; KEY_LEN] = key.try_into().map_err(|_| KeyStoreError::UnwrapFailed(image_id.to_string()))?;
+        if data_key_id(&key) != wrapped.data_key_id {
+            return Err(KeyStoreError::WrongDataKey(wrapped.data_key_id.clone()));
+        }
+
+        Ok(self.insert(BlockKey::new(&wrapped.data_key_id, key)?))
+    }
+}
+
+// Generate a random data encryption key for a single image
+pub fn generate_data_key() -> BlockKey {
+    let mut key = [0u8; KEY_LEN];
+    OsRng.fill_bytes(&mut key);
+    BlockKey::new(&data_key_id(&key), key).expect("Data key id is always valid")
+}
+
+// The id of a data key is a fingerprint of the key, so every image gets a distinct id
+// and a reconstructed key can be checked against it
+pub fn data_key_id(key: &[u8; KEY_LEN]) -> String {
+    let fingerprint = Sha256::digest(key);
+    format!("dek-{}", hex::encode(&fingerprint[..8]))
+}
+
+// Wrap a data key with a master key, binding it to the image id
+pub fn wrap_data_key(data_key: &BlockKey, master_key: &BlockKey, image_id: &str) -> WrappedKey {
+    let mut nonce = [0u8; WRAP_NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+
+    let cipher = Aes128Gcm::new(master_key.bytes().into());
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: data_key.bytes(), aad: image_id.as_bytes() })
+        .expect("Failed to wrap data key");
+
+    WrappedKey {
+        master_key_id: master_key.id().to_string(),
+        data_key_id: data_key.id().to_string(),
+        nonce,
+        ciphertext,
+    }
+}
+
+pub fn derive_key(passphrase: &str, salt: &[u8; SALT_LEN]) -> Result<[u8; KEY_LEN], KeyStoreError> {
+    let mut key = [0u8; KEY_LEN];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(KeyStoreError::Derivation)?;
+    Ok(key)
+}
+
+fn load_or_create_salt(salt_path: &Path) -> Result<[u8; SALT_LEN], KeyStoreError> {
+    if salt_path.exists() {
+        return read_fixed::<SALT_LEN>(salt_path);
+    }
+
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    fs::write(salt_path, salt)?;
+    Ok(salt)
+}
+
+fn read_fixed<const N: usize>(path: &Path) -> Result<[u8; N], KeyStoreError> {
+    let data = fs::read(path)?;
+    data.as_slice().try_into().map_err(|_| KeyStoreError::InvalidLength {
+        path: path.to_path_buf(),
+        expected: N,
+        actual: data.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_encryption::{decrypt_block_data, encrypt_block_data, BlockEncryptionError};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("key_store_{}_{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn passphrase_keys_are_derived_again_from_the_stored_salt() {
+        let salt_path = temp_path("salt");
+        let mut key_store = KeyStore::new();
+        let first = *key_store.add_passphrase_key("department", "passphrase", &salt_path).unwrap().bytes();
+        let second = *key_store.add_passphrase_key("department", "passphrase", &salt_path).unwrap().bytes();
+        assert_eq!(first, second);
+        assert_eq!(fs::read(&salt_path).unwrap().len(), SALT_LEN);
+
+        // Another salt gives another key
+        fs::remove_file(&salt_path).unwrap();
+        let third = *key_store.add_passphrase_key("department", "passphrase", &salt_path).unwrap().bytes();
+        assert_ne!(first, third);
+        fs::remove_file(salt_path).unwrap();
+    }
+
+    #[test]
+    fn key_files_must_hold_a_whole_key() {
+        let key_path = temp_path("key");
+        let mut key_store = KeyStore::new();
+        for length in [KEY_LEN - 1, KEY_LEN + 1] {
+            fs::write(&key_path, vec![1u8; length]).unwrap();
+            assert!(matches!(
+                key_store.load_key_file("department", &key_path),
+                Err(KeyStoreError::InvalidLength { expected: KEY_LEN, actual, .. }) if actual == length
+            ));
+        }
+
+        fs::write(&key_path, [1u8; KEY_LEN]).unwrap();
+        assert_eq!(key_store.load_key_file("department", &key_path).unwrap().bytes(), &[1u8; KEY_LEN]);
+        fs::remove_file(key_path).unwrap();
+    }
+
+    #[test]
+    fn key_ids_must_fit_their_length_byte() {
+        assert!(matches!(BlockKey::new("", [0; KEY_LEN]), Err(KeyStoreError::InvalidKeyId(_))));
+        assert!(matches!(BlockKey::new(&"k".repeat(256), [0; KEY_LEN]), Err(KeyStoreError::InvalidKeyId(_))));
+        assert!(BlockKey::new(&"k".repeat(255), [0; KEY_LEN]).is_ok());
+    }
+
+    #[test]
+    fn blocks_are_decrypted_with_the_key_named_in_their_header() {
+        let mut key_store = KeyStore::new();
+        let keys: Vec<BlockKey> = (0..3).map(|i| key_store.insert(BlockKey::new(&format!("department-{}", i), [i; KEY_LEN]).unwrap()).clone()).collect();
+
+        for (i, key) in keys.iter().enumerate() {
+            let samples = vec![i as u8; 16];
+            let encrypted = encrypt_block_data(&samples, (2, 2), key, "image", 0);
+            assert_eq!(decrypt_block_data(&encrypted, &key_store, "image", 0, (2, 2)).unwrap(), samples);
+        }
+
+        // Blocks of a department missing from the store are not decrypted with another key
+        let missing = BlockKey::new("department-3", [3; KEY_LEN]).unwrap();
+        let encrypted = encrypt_block_data(&[0; 16], (2, 2), &missing, "image", 0);
+        assert!(matches!(
+            decrypt_block_data(&encrypted, &key_store, "image", 0, (2, 2)),
+            Err(BlockEncryptionError::KeyStore(KeyStoreError::UnknownKey(id))) if id == "department-3"
+        ));
+    }
+
+    fn master_key() -> BlockKey {
+        BlockKey::new("department", [4; KEY_LEN]).unwrap()
+    }
+
+    #[test]
+    fn wrapped_data_keys_unwrap_to_the_data_key() {
+        let data_key = generate_data_key();
+        let wrapped = wrap_data_key(&data_key, &master_key(), "image");
+
+        let mut key_store = KeyStore::new();
+        key_store.insert(master_key());
+        assert_eq!(key_store.unwrap_data_key(&wrapped, "image").unwrap().bytes(), data_key.bytes());
+        assert_eq!(key_store.get(data_key.id()).unwrap().bytes(), data_key.bytes());
+    }
+
+    #[test]
+    fn wrapped_data_keys_are_bound_to_their_image_and_master_key() {
+        let wrapped = wrap_data_key(&generate_data_key(), &master_key(), "image");
+
+        let mut key_store = KeyStore::new();
+        key_store.insert(master_key());
+        assert!(matches!(key_store.unwrap_data_key(&wrapped, "other image"), Err(KeyStoreError::UnwrapFailed(_))));
+
+        // Another master key under the same id, or no master key at all
+        let mut other_store = KeyStore::new();
+        other_store.insert(BlockKey::new("department", [5; KEY_LEN]).unwrap());
+        assert!(matches!(other_store.unwrap_data_key(&wrapped, "image"), Err(KeyStoreError::UnwrapFailed(_))));
+        assert!(matches!(KeyStore::new().unwrap_data_key(&wrapped, "image"), Err(KeyStoreError::UnknownKey(_))));
+    }
+
+    #[test]
+    fn relabelled_wrapped_keys_are_rejected() {
+        let mut wrapped = wrap_data_key(&generate_data_key(), &master_key(), "image");
+        let other_id = generate_data_key().id().to_string();
+        wrapped.data_key_id = other_id.clone();
+
+        let mut key_store = KeyStore::new();
+        key_store.insert(master_key());
+        assert!(matches!(key_store.unwrap_data_key(&wrapped, "image"), Err(KeyStoreError::WrongDataKey(id)) if id == other_id));
+        assert!(key_store.get(&other_id).is_err());
+    }
+}
@@ -5,18 +5,45 @@ mod ipfs_upload;
 mod merkle_tree;
 mod blockchain;
 mod image_verification;
-
-use image_to_msb::{extract_msb, convert_msb_to_normal};
-use image_to_chunks::{slice_image_into_blocks,save_blocks};
-use block_encryption::{encrypt_and_save_blocks, decrypt_block};
-use merkle_tree::{insert_root,  build_tree, MerkleTree};
+mod key_store;
+mod key_rotation;
+mod key_sharing;
+mod disclosure;
+mod perceptual_hash;
+mod dct;
+mod dct_features;
+mod feature_extractor;
+mod canonicalize;
+mod quadtree;
+mod pyramid;
+mod tiled_tiff;
+mod shifted_grid;
+
+use image_to_msb::{is_16_bit, LumaStandard, MsbOptions};
+use dct_features::DctOptions;
+use canonicalize::{canonical_color, load_canonical_image, read_orientation, CanonicalColor};
+use feature_extractor::{BlurredLumaOptions, FeatureExtractor, RawPixels};
+use image_to_chunks::{block_views, save_block, BlockView, BlockGrid, BlockLayout, BlockPartition, BlockRegion, Padding};
+use quadtree::{QuadtreeOptions, QuadtreePartition};
+use pyramid::{level_image_id, merge_leaves, refine_selection, PyramidLevel};
+use tiled_tiff::TiledTiff;
+use shifted_grid::{localize_tampering, shifted_image_id, ShiftedGrid};
+use block_encryption::{seal_block, save_encrypted_block, decrypt_block};
+use key_store::{generate_data_key, wrap_data_key, BlockKey, KeyStore};
+use key_sharing::{split_data_key, KeyProtection, KeyShare, KeySharingError};
+use key_rotation::rotate_block_keys;
+use disclosure::{export_disclosure, open_disclosure, DisclosureBundle};
+use merkle_tree::{insert_root, build_tree, prove_block};
 use ipfs_upload::{upload_to_ipfs, download_file_from_ipfs};
-use blockchain::{Blockchain, return_transaction};
-use image_verification::image_verification;
+use blockchain::{Block, Blockchain, Transaction, return_transaction};
+use image_verification::{image_verification, ignore_perceptual_changes};
+use perceptual_hash::{make_leaf, perceptual_hash, LeafMode};
 use std::path::Path;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use rayon::prelude::*;
 use sha2::Sha256;
 use sha2::Digest;
-use image::{GenericImageView, ImageBuffer, Rgba};
+use image::{ColorType, DynamicImage, GenericImageView, ImageBuffer, Pixel, Rgba};
 
 #[tokio::main]
 async fn main() {
@@ -25,142 +52,717 @@ async fn main() {
     let original_prefix = "original";
     let deprecated_image_path = "Path of the image with tampered blocks";
     let deprecated_prefix = "fake";
-    let block_size = "Size of the block";
+    let block_width: u32 = 32; // Width of the blocks
+    let block_height: u32 = 32; // Height of the blocks, e.g. smaller than the width for document strips
+    // Partial blocks on the right and bottom edges are padded with zeros or by repeating the last
+    // row and column. The padding is recorded in the registration and verification uses it.
+    let padding = match "Padding of the edge blocks (zero or edge)" {
+        "edge" => Padding::Edge,
+        _ => Padding::Zero,
+    };
+    let layout = BlockLayout { block_width, block_height, padding };
+    let quadtree_options = QuadtreeOptions {
+        min_block_size: 8,    // Smallest block of the adaptive partition
+        max_deviation: 20.0,  // Luminance standard deviation above which a block is split
+    };
+    // Square block sizes also registered for coarse-to-fine verification, coarsest first, e.g. vec![64, 32, 16]
+    let pyramid_sizes: Vec<u32> = Vec::new();
+    let pyramid_depth = pyramid_sizes.len(); // Number of pyramid levels the verifier can afford to drill into
+    // Register a second grid offset by half a block, so that edits aligned with the blocks of the
+    // first grid are caught and tampering is localized to quarter blocks
+    let with_shifted_grid = "Register a shifted grid (yes or no)" == "yes";
+    let msb_options = MsbOptions {
+        bits: 1,              // Number of most significant bits kept per channel, from 1 to 16
+        include_alpha: false, // Set for images whose transparency must be authenticated
+        luma: LumaStandard::from_name("Luma standard to authenticate (bt601 or bt709), or rgb").expect("Failed to parse luma standard"),
+    };
+    let dct_options = DctOptions {
+        coefficients: 6,   // Number of low frequency DCT coefficients kept per 8x8 cell
+        quantization: 1.0, // Scale of the JPEG quantization table
+    };
+    let blurred_luma_options = BlurredLumaOptions {
+        blur: 1.5, // Standard deviation of the blur applied to the luminance
+        bits: 2,   // Number of most significant bits of the blurred luminance kept
+    };
+
+    // Feature extractor the original image is registered with. It is recorded in the registration
+    // and verification uses the recorded one.
+    // Blurred luminance is unrelated to perceptual leaves, which can be combined with any extractor.
+    let extractor: Box<dyn FeatureExtractor> = match "Feature extractor (msb, dct, blurred or raw)" {
+        "dct" => Box::new(dct_options),
+        "blurred" => Box::new(blurred_luma_options),
+        "raw" => Box::new(RawPixels),
+        _ => Box::new(msb_options),
+    };
+
+    // Leaves are exact MACs of the blocks unless a perceptual threshold is set, e.g. Some(10) to
+    // tolerate JPEG recompression of the suspect image. It is recorded in the registration and
+    // verification uses the recorded one.
+    let perceptual_threshold: Option<u32> = None;
+    let leaf_mode = match perceptual_threshold {
+        Some(max_distance) => LeafMode::Perceptual { max_distance },
+        None => LeafMode::Exact,
+    };
+
+    // Blocks are encrypted on every core; uploads to IPFS are bounded so the node is not flooded
+    let upload_concurrency = 8;
+
+    // Both images are authenticated under the id of the registered image so that
+    // unchanged blocks produce identical MACs
+    let image_id = "Identifier of the registered image";
+
+    // Load the master key of the registering department: a raw key file if one exists,
+    // otherwise a key derived from the department passphrase
+    let key_id = "Identifier of the department key";
+    let key_file_path = Path::new("Path of the department key file");
+    let mut key_store = KeyStore::new();
+    let master_key = if key_file_path.exists() {
+        key_store.load_key_file(key_id, key_file_path)
+    } else {
+        key_store.add_passphrase_key(key_id, "Passphrase of the department", Path::new("Path of the salt file"))
+    }
+    .expect("Failed to load department key")
+    .clone();
+
+    // Every registered image gets its own data key. It is wrapped by the department master key,
+    // or split between custodians so that the original blocks can only be restored when
+    // `threshold` of them approve. The protection is recorded in the registration.
+    // The suspect image is encrypted with the same data key so unchanged blocks match.
+    let data_key = generate_data_key();
+    let threshold = 3; // Number of custodians required to restore original blocks
+    let custodians = 5; // Number of custodians holding a share of the data key
+    let (key_protection, key_shares) = match "Data key protection (wrapped or shared)" {
+        "wrapped" => (KeyProtection::Wrapped(wrap_data_key(&data_key, &master_key, image_id)), Vec::new()),
+        _ => {
+            let (key_sharing, key_shares) = split_data_key(&data_key, threshold, custodians).expect("Failed to split data key");
+            (KeyProtection::Shared(key_sharing), key_shares)
+        }
+    };
+
+    // Huge tiled TIFFs are never decoded whole: their blocks are their tiles, read one at a time.
+    // Extractors whose features depend on neighbouring tiles process the decoded image instead.
+    let tiled_original = TiledTiff::open(original_image_path).ok().filter(|_| extractor.supports_tiles());
+    let original_is_tiled = tiled_original.is_some();
+
+    // Blocks form a uniform grid, or the grid blocks are split where the original image is detailed.
+    // The adaptive partition is recorded in the registration and the suspect image is sliced along it.
+    let partition = if let Some(tiff) = &tiled_original {
+        BlockPartition::Grid(tiff.tile_grid(padding).layout)
+    } else {
+        match "Block partition (grid or quadtree)" {
+            "quadtree" => {
+                let luma = load_canonical_image(original_image_path).expect("Failed to open image").to_luma8();
+                BlockPartition::Quadtree(QuadtreePartition::build(&luma, layout, quadtree_options))
+            }
+            _ => BlockPartition::Grid(layout),
+        }
+    };
 
-    // Process both images
-    let leaves_original = process_image(original_image_path, block_size, original_prefix).await;
-    let leaves_fake = process_image(deprecated_image_path, block_size, deprecated_prefix).await;
+    // Process the original image
+    let processing = Processing { extractor: extractor.as_ref(), leaf_mode, upload_concurrency };
+    let original = match tiled_original {
+        Some(tiff) => process_tiled_image(tiff, &data_key, image_id, &processing, padding, original_prefix).await,
+        None => process_image(original_image_path, &data_key, image_id, &processing, &partition, None, original_prefix).await,
+    }
+    .expect("Failed to upload the blocks of the original image");
+    // Other block sizes and grids would need the whole image: tiled images only register their tiles
+    let pyramid = if original_is_tiled {
+        Vec::new()
+    } else {
+        register_pyramid(original_image_path, &data_key, image_id, &processing, &pyramid_sizes, padding, original_prefix)
+            .await
+            .expect("Failed to upload the pyramid blocks")
+    };
+    let shifted = match &partition {
+        BlockPartition::Grid(layout) if with_shifted_grid && !original_is_tiled => {
+            let shifted = register_shifted_grid(original_image_path, &data_key, image_id, &processing, *layout, original_prefix).await;
+            Some(shifted.expect("Failed to upload the shifted grid blocks"))
+        }
+        _ => None,
+    };
 
     // Initialize a blockchain
     let mut blockchain = Blockchain::new();
 
-    // Insert leaves_original in the Transaction of the blockchain
-    insert_root(leaves_original.clone(), &mut blockchain);
-
-    // Calculate fake merkle tree and return it
-    let fake_merkle_tree = build_tree(leaves_fake);
+    // Blocks are sliced from the upright image: record the orientation the original was stored with
+    let original_orientation = read_orientation(original_image_path).expect("Failed to read image orientation");
+
+    // 16-bit images in another colour space than sRGB are registered in their own colour space
+    let original_color = canonical_color(original_image_path).expect("Failed to read image colour space");
+
+    // Insert leaves_original, the key sharing policy, the block format, the feature extractor, the leaf mode and the block partition in the Transaction of the blockchain
+    let registration = Transaction {
+        tx: original.leaves,
+        cids: original.cids,
+        supersedes: None,
+        key_protection,
+        block_color: original.block_color,
+        extractor: extractor.spec(),
+        leaf_mode,
+        orientation: original_orientation,
+        canonical_color: original_color,
+        grid: original.grid,
+        quadtree: match partition {
+            BlockPartition::Quadtree(quadtree) => Some(quadtree),
+            BlockPartition::Grid(_) | BlockPartition::Shifted(_) => None,
+        },
+        pyramid,
+        shifted,
+    };
+    insert_root(registration, &mut blockchain);
+    blockchain.print_blockchain();
 
     // Get the transaction of the block by calculating the hash of the header
     let last_block_hash = blockchain::calculate_hash(&blockchain.chain.last().unwrap().header);
+    let registered_block = blockchain.find_block(&last_block_hash).expect("Registration not found");
+    let registered_transaction = registered_block.transaction.as_ref().expect("Block is not a registration");
+
+    // Process the suspect image with the feature extractor, leaf mode and block partition recorded in the registration
+    let registered_extractor = registered_transaction.feature_extractor();
+    let registered_leaf_mode = registered_transaction.leaf_mode;
+    let registered_processing = Processing {
+        extractor: registered_extractor.as_ref(),
+        leaf_mode: registered_leaf_mode,
+        upload_concurrency,
+    };
+    let registered_partition = registered_transaction.block_partition();
+    let fake = match TiledTiff::open(deprecated_image_path) {
+        Ok(tiff) if registered_extractor.supports_tiles() && registered_partition == BlockPartition::Grid(tiff.tile_grid(registered_partition.layout().padding).layout) => {
+            process_tiled_image(tiff, &data_key, image_id, &registered_processing, registered_partition.layout().padding, deprecated_prefix).await
+        }
+        _ => process_image(deprecated_image_path, &data_key, image_id, &registered_processing, &registered_partition, None, deprecated_prefix).await,
+    }
+    .expect("Failed to upload the blocks of the suspect image");
+
+    // Block indices only refer to the same areas when both images have the same grid
+    if !registered_transaction.grid.is_aligned_with(&fake.grid) {
+        println!(
+            "Suspect image is {}x{}, the registered image {}x{}: block indices do not refer to the same areas",
+            fake.grid.width, fake.grid.height, registered_transaction.grid.width, registered_transaction.grid.height
+        );
+    }
+
+    // A copy that baked the rotation into its pixels has the same upright grid as the original
+    let suspect_orientation = read_orientation(deprecated_image_path).expect("Failed to read image orientation");
+    if registered_transaction.orientation != suspect_orientation {
+        println!(
+            "Suspect image is stored with orientation {:?}, the registered image with {:?}: both are compared upright",
+            suspect_orientation, registered_transaction.orientation
+        );
+    }
+
+    // Pixels kept in the colour space of their file only match copies in the same colour space
+    let suspect_color = canonical_color(deprecated_image_path).expect("Failed to read image colour space");
+    if let CanonicalColor::AsDecoded(color_space) = &registered_transaction.canonical_color {
+        println!("Registered image was not converted to sRGB and is compared in its {}", color_space);
+    }
+    if registered_transaction.canonical_color != suspect_color {
+        println!(
+            "Suspect image is in {:?}, the registered image in {:?}: blocks of different colour spaces are reported as tampered",
+            suspect_color, registered_transaction.canonical_color
+        );
+    }
+
+    // Calculate fake merkle tree and return it
+    let fake_merkle_tree = build_tree(fake.leaves.clone(), registered_transaction.tree_shape().as_ref());
 
     // Return leaves of the original image
     let original_transactions = return_transaction(&blockchain, &last_block_hash);
 
     // Merkle tree from original leaves
-    let original_merkle_tree = build_tree(original_transactions);
+    let original_merkle_tree = build_tree(original_transactions.clone(), registered_transaction.tree_shape().as_ref());
 
     // Perform image verification and get the `ri` array
-    let ri = image_verification(fake_merkle_tree, original_merkle_tree);
+    let mut ri = image_verification(fake_merkle_tree, original_merkle_tree);
+    ignore_perceptual_changes(&mut ri, &original_transactions, &fake.leaves, registered_leaf_mode);
+
+    // Localize tampering coarsely first, then only in flagged areas at the finer block sizes
+    if let Some((pyramid_grid, pyramid_ri)) = verify_pyramid(deprecated_image_path, &data_key, image_id, registered_block, &registered_processing, pyramid_depth, deprecated_prefix).await {
+        let tampered: Vec<_> = pyramid_grid.regions().into_iter().zip(&pyramid_ri).filter(|(_, &r)| r == 1).map(|(region, _)| region).collect();
+        println!("Tampered regions at the finest pyramid level: {:?}", tampered);
+    }
+
+    // Areas flagged on both the grid and the shifted grid
+    let registered_layout = registered_transaction.block_layout();
+    let shifted_ri = verify_shifted_grid(deprecated_image_path, &data_key, image_id, registered_block, &registered_processing, registered_layout, deprecated_prefix).await;
+    if let Some(shifted_ri) = shifted_ri {
+        println!("Tampered areas on both grids: {:?}", localize_tampering(&registered_transaction.grid, &ri, &shifted_ri));
+    }
+
+    // Custodians approving the restoration hand in their shares; wrapped data keys are unwrapped
+    // with the department master key instead
+    let approving_shares = &key_shares[..key_shares.len().min(threshold as usize)];
 
     // Restore the tampered blocks
-    let restored_image = restore_tampered_blocks(original_image_path, &leaves_original, &ri, block_size).await;
+    let restored_image = restore_tampered_blocks(original_image_path, registered_transaction, &mut key_store, approving_shares, image_id, &ri)
+        .await
+        .expect("Failed to restore tampered blocks");
 
     // Save the restored image
     restored_image.save("Path of the restored image").expect("Failed to save restored image");
+
+    // Disclose a range of blocks to a third party without revealing the rest of the image
+    let disclosed_blocks: Vec<u32> = (11..20).collect(); // Blocks 12-20
+    let bundle_path = Path::new("Path of the disclosure bundle");
+    export_disclosure(registered_block, &data_key, image_id, &disclosed_blocks)
+        .and_then(|bundle| bundle.save(bundle_path))
+        .expect("Failed to export disclosure bundle");
+
+    // The third party checks the bundle against the on-chain merkle root before decrypting
+    let bundle = DisclosureBundle::load(bundle_path).expect("Failed to load disclosure bundle");
+    let disclosed = open_disclosure(&bundle, &registered_block.header.merkle_root).await.expect("Failed to open disclosure bundle");
+    for (index, block) in disclosed {
+        let file_name = format!("Disclosed_block_{}.png", index + 1);
+        block.save(&file_name).expect("Failed to save disclosed block");
+    }
+
+    // Anyone holding the on-chain merkle root can check that a single block belongs to the
+    // registration from its inclusion proof, without the other leaves
+    let inclusion_proof = prove_block(registered_transaction, 0).expect("Registration has no blocks");
+    println!(
+        "Inclusion proof of block 1 ({} sibling hashes) is valid: {}",
+        inclusion_proof.siblings.len(),
+        inclusion_proof.verify(&registered_block.header.merkle_root)
+    );
+
+    // Rotate the data key: re-encrypt the registered blocks under a new data key, protected like
+    // the current one, and record them in a block that supersedes the original registration
+    let rotated = rotate_block_keys(&mut blockchain, &last_block_hash, &mut key_store, image_id, "rotated")
+        .await
+        .expect("Failed to rotate block keys");
+    println!("Registration {} superseded by {}", last_block_hash, rotated.block_hash);
+    println!("Distributing {} new key shares to the custodians", rotated.key_shares.len());
+
+    // Verifiers holding the original registration hash are pointed to the rotated one
+    let latest_registration = blockchain.latest_registration(&last_block_hash).expect("Registration not found");
+    println!("Current merkle root of the registration: {}", latest_registration.header.merkle_root);
 }
 
-// Function to process an image: extract MSB, slice into blocks, encrypt, upload to IPFS, and collect hashes
-async fn process_image(image_path: &str, block_size: u32, prefix: &str) -> Vec<String> {
-    // Extract MSB from image and create image from MSBs
-    let msb_img = extract_msb(image_path);
+// How the features and leaves of an image are computed: the same for the registered and the suspect image
+struct Processing<'a> {
+    extractor: &'a dyn FeatureExtractor,
+    leaf_mode: LeafMode,
+    upload_concurrency: usize, // Number of blocks uploaded to IPFS at the same time
+}
 
-    // Break the image into blocks
-    let blocks = slice_image_into_blocks(&msb_img, block_size);
+// Leaves of a processed image, the IPFS hashes of its encrypted blocks, the color type of the
+// blocks and the grid they were sliced on
+struct ProcessedImage {
+    leaves: Vec<String>,
+    cids: Vec<String>,
+    block_color: ColorType,
+    grid: BlockGrid,
+}
 
-    //save blocks
-    save_blocks(blocks.clone(),prefix);
+// Function to process an image: extract MSB, slice into blocks, encrypt, upload to IPFS, and collect
+// the MACs of the blocks as leaves. Only the blocks in `selection` are encrypted and uploaded when it is set.
+// Fails when a block cannot be uploaded, as the registration would not hold every block.
+async fn process_image(image_path: &str, key: &BlockKey, image_id: &str, processing: &Processing<'_>, partition: &BlockPartition, selection: Option<&[bool]>, prefix: &str) -> Result<ProcessedImage, ipfs_api::Error> {
+    let extractor = processing.extractor;
+
+    // Extract the features of the image, e.g. its MSBs
+    let msb_img = extractor.extract(image_path);
+    let block_color = msb_img.color();
+    let grid = BlockGrid::new(msb_img.width(), msb_img.height(), partition.layout());
+
+    // Features must not straddle two blocks, including blocks of shifted grids
+    let cell_size = extractor.cell_size();
+    for region in partition.regions(msb_img.width(), msb_img.height()) {
+        assert!(
+            [region.x, region.y, region.width, region.height].iter().all(|value| value.is_multiple_of(cell_size)),
+            "blocks must start and end on multiples of {}, got {:?}",
+            cell_size,
+            region
+        );
+    }
 
-    // Encrypt each block and save to file with the given prefix
-    encrypt_and_save_blocks(&blocks, prefix);
+    // Perceptual hashes are computed on the full image rather than on its MSBs
+    let gray_image = match processing.leaf_mode {
+        LeafMode::Exact => None,
+        LeafMode::Perceptual { .. } => Some(DynamicImage::ImageLuma8(load_canonical_image(image_path).expect("Failed to open image").to_luma8())),
+    };
 
-    // Vector for storing hashes of the blocks as leaves
-    let mut leaves = Vec::new();
+    // Encrypt the blocks and save them to files with the given prefix
+    let sealed_blocks = seal_blocks(&msb_img, gray_image.as_ref(), partition, key, image_id, selection, |i, block, encrypted_block| {
+        save_block(block, prefix, i);
+        save_encrypted_block(encrypted_block, i as u32, prefix);
+    });
+
+    // Upload the encrypted blocks to IPFS and get their hashes
+    let (indices, leaves): (Vec<usize>, Vec<String>) = sealed_blocks.into_iter().unzip();
+    let cids = upload_encrypted_blocks(prefix, indices, processing.upload_concurrency).await?;
+
+    Ok(ProcessedImage { leaves, cids, block_color, grid })
+}
 
-    // Upload encrypted blocks to IPFS and get their hashes
-    for i in 0..blocks.len() {
-        let file_name = format!("{}_block_{}.enc", prefix, i + 1);
-        let file_path = Path::new(&file_name);
-
-        // Read the encrypted block file and print its hash
-        let encrypted_block = std::fs::read(file_path).expect("Failed to read encrypted block file");
-        let mut hasher = Sha256::new();
-        hasher.update(&encrypted_block);
-        let block_hash = hasher.finalize();
-        println!("Block {}: File hash before upload: {}", i + 1, hex::encode(block_hash));
-
-        match upload_to_ipfs(file_path).await {
-            Ok(hash) => {
-                println!("Uploaded to IPFS with Block_no and Hash {}: {}", i + 1, hash);
-                leaves.push(hash);
+// Encrypt the blocks of the features in `selection`, or all of them, and compute their leaves.
+// Blocks past the end of the selection are encrypted too.
+// Blocks are copied out of the image, encrypted and hashed in parallel, each block being only
+// held by the thread processing it while `store` saves it. Leaves are returned with their block
+// index in block order, whatever the scheduling.
+fn seal_blocks<F>(features: &DynamicImage, gray_image: Option<&DynamicImage>, partition: &BlockPartition, key: &BlockKey, image_id: &str, selection: Option<&[bool]>, store: F) -> Vec<(usize, String)>
+where
+    F: Fn(usize, &DynamicImage, &[u8]) + Sync,
+{
+    let gray_views: Option<Vec<BlockView>> = gray_image.map(|gray_image| block_views(gray_image, partition).collect());
+    let views: Vec<BlockView> = block_views(features, partition).collect();
+    views
+        .into_par_iter()
+        .enumerate()
+        .filter(|(i, _)| selection.is_none_or(|selection| selection.get(*i).copied().unwrap_or(true)))
+        .map(|(i, view)| {
+            let block = view.to_image();
+            let (encrypted_block, mac) = seal_block(&block, key, image_id, i as u32);
+            store(i, &block, &encrypted_block);
+
+            let perceptual_hash = gray_views.as_ref().map(|views| perceptual_hash(&views[i].to_image().to_luma8()));
+            (i, make_leaf(perceptual_hash, &mac))
+        })
+        .collect()
+}
+
+// Process a tiled TIFF on the grid of its tiles, holding one tile in memory at a time: each tile
+// is decoded, its features extracted, then encrypted and uploaded like a block of `process_image`
+async fn process_tiled_image(mut tiff: TiledTiff, key: &BlockKey, image_id: &str, processing: &Processing<'_>, padding: Padding, prefix: &str) -> Result<ProcessedImage, ipfs_api::Error> {
+    let extractor = processing.extractor;
+    let tile_grid = tiff.tile_grid(padding);
+    let partition = BlockPartition::Grid(tile_grid.layout);
+
+    // Features must not straddle two tiles
+    let cell_size = extractor.cell_size();
+    assert!(
+        tiff.tile_width.is_multiple_of(cell_size) && tiff.tile_height.is_multiple_of(cell_size),
+        "TIFF tiles must be multiples of {}, got {}x{}",
+        cell_size,
+        tiff.tile_width,
+        tiff.tile_height
+    );
+
+    let mut leaves = Vec::new();
+    let mut block_color = ColorType::Rgba8;
+    let (mut width, mut height) = (0, 0);
+    for i in 0..tile_grid.block_count() {
+        let tile = tiff.read_tile(i).expect("Failed to read TIFF tile");
+        let features = extractor.extract_tile(&tile).expect("Feature extractor cannot process the image tile by tile");
+        block_color = features.color();
+
+        // The features of the whole image span the features of its last tiles
+        let (x, y) = tile_grid.block_origin(i as u32);
+        width = width.max(x + features.width());
+        height = height.max(y + features.height());
+
+        // Each tile is one block, padded to the tile size on the right and bottom edges
+        let block = block_views(&features, &partition).next().expect("Tile has no block").to_image();
+        save_block(&block, prefix, i);
+        let (encrypted_block, mac) = seal_block(&block, key, image_id, i as u32);
+        save_encrypted_block(&encrypted_block, i as u32, prefix);
+
+        let perceptual_hash = match processing.leaf_mode {
+            LeafMode::Exact => None,
+            LeafMode::Perceptual { .. } => {
+                let gray_tile = DynamicImage::ImageLuma8(tile.to_luma8());
+                let gray_block = block_views(&gray_tile, &partition).next().expect("Tile has no block").to_image();
+                Some(perceptual_hash(&gray_block.to_luma8()))
             }
-            Err(e) => eprintln!("Error uploading to IPFS: {}", e),
+        };
+        leaves.push(make_leaf(perceptual_hash, &mac));
+    }
+
+    let cids = upload_encrypted_blocks(prefix, (0..leaves.len()).collect(), processing.upload_concurrency).await?;
+    Ok(ProcessedImage { leaves, cids, block_color, grid: BlockGrid::new(width, height, tile_grid.layout) })
+}
+
+// Upload the encrypted files of the blocks, at most `concurrency` at a time, and return their
+// IPFS hashes in block order. Fails on the first block whose upload fails.
+async fn upload_encrypted_blocks(prefix: &str, blocks: Vec<usize>, concurrency: usize) -> Result<Vec<String>, ipfs_api::Error> {
+    stream::iter(blocks)
+        .map(|i| async move { upload_encrypted_block(prefix, i).await })
+        .buffered(concurrency.max(1))
+        .try_collect()
+        .await
+}
+
+// Upload the encrypted file of block `i` to IPFS and return its hash
+async fn upload_encrypted_block(prefix: &str, i: usize) -> Result<String, ipfs_api::Error> {
+    let file_name = format!("{}_block_{}.enc", prefix, i + 1);
+    let file_path = Path::new(&file_name);
+
+    // Read the encrypted block file and print its hash
+    let encrypted_block = std::fs::read(file_path).expect("Failed to read encrypted block file");
+    let mut hasher = Sha256::new();
+    hasher.update(&encrypted_block);
+    let block_hash = hasher.finalize();
+    println!("Block {}: File hash before upload: {}", i + 1, hex::encode(block_hash));
+
+    match upload_to_ipfs(file_path).await {
+        Ok(hash) => {
+            println!("Uploaded to IPFS with Block_no and Hash {}: {}", i + 1, hash);
+            Ok(hash)
+        }
+        Err(e) => {
+            eprintln!("Error uploading block {} to IPFS: {}", i + 1, e);
+            Err(e)
         }
     }
+}
+
+// Register the leaves of an image at additional square block sizes, coarsest first.
+// Each level is encrypted under its own image id.
+async fn register_pyramid(image_path: &str, key: &BlockKey, image_id: &str, processing: &Processing<'_>, sizes: &[u32], padding: Padding, prefix: &str) -> Result<Vec<PyramidLevel>, ipfs_api::Error> {
+    let mut levels = Vec::with_capacity(sizes.len());
+    for &size in sizes {
+        let layout = BlockLayout { block_width: size, block_height: size, padding };
+        let level_prefix = format!("{}_level{}x{}", prefix, size, size);
+        let level = process_image(image_path, key, &level_image_id(image_id, layout), processing, &BlockPartition::Grid(layout), None, &level_prefix).await?;
 
-    leaves
+        levels.push(PyramidLevel { grid: level.grid, leaves: level.leaves, cids: level.cids });
+    }
+    Ok(levels)
 }
 
-// Function to restore tampered blocks
-async fn restore_tampered_blocks(original_image_path: &str, leaves_original: &[String], ri: &Vec<u32>, block_size: u32) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
-    // Load the original image
-    let original_image = image::open(original_image_path).expect("Failed to open original image");
+// Register the leaves of an image on a grid offset by half a block, encrypted under its own image id
+async fn register_shifted_grid(image_path: &str, key: &BlockKey, image_id: &str, processing: &Processing<'_>, layout: BlockLayout, prefix: &str) -> Result<ShiftedGrid, ipfs_api::Error> {
+    let shifted_prefix = format!("{}_shifted", prefix);
+    let shifted = process_image(image_path, key, &shifted_image_id(image_id, layout), processing, &BlockPartition::Shifted(layout), None, &shifted_prefix).await?;
 
-    // Convert DynamicImage to ImageBuffer<Rgba<u8>, Vec<u8>>
-    let original_image_buffer = original_image.to_rgba8();
-    let (width, height) = original_image_buffer.dimensions();
-    let mut restored_image = ImageBuffer::new(width, height);
+    Ok(ShiftedGrid { leaves: shifted.leaves, cids: shifted.cids })
+}
 
-    // Define transparent red color
-    let transparent_red = Rgba([255, 0, 0, 128]);
+// Compare the suspect image with the registered shifted grid and return its tampered result array.
+// The shifted grid is checked against the root in the block header.
+async fn verify_shifted_grid(image_path: &str, key: &BlockKey, image_id: &str, registration: &Block, processing: &Processing<'_>, layout: BlockLayout, prefix: &str) -> Option<Vec<u32>> {
+    let shifted = registration.transaction.as_ref()?.shifted.as_ref()?;
+    let registered_tree = build_tree(shifted.leaves.clone(), None);
+    if registered_tree.root_hex() != registration.header.shifted_root {
+        eprintln!("Shifted grid does not match its registered merkle root");
+        return None;
+    }
 
-    // Iterate over the `ri` array
-    for (i, &r) in ri.iter().enumerate() {
-        // Calculate the position of the block in the image
-        let x = (i as u32 % (width / block_size)) * block_size;
-        let y = (i as u32 / (width / block_size)) * block_size;
+    let shifted_prefix = format!("{}_shifted", prefix);
+    let leaves = process_image(image_path, key, &shifted_image_id(image_id, layout), processing, &BlockPartition::Shifted(layout), None, &shifted_prefix)
+        .await
+        .expect("Failed to upload the shifted blocks of the suspect image")
+        .leaves;
 
+    let mut ri = image_verification(build_tree(leaves.clone(), None), registered_tree);
+    ignore_perceptual_changes(&mut ri, &shifted.leaves, &leaves, processing.leaf_mode);
+    ri.truncate(shifted.leaves.len());
+    Some(ri)
+}
+
+// Compare the suspect image with the registered pyramid from the coarsest level down to `depth`
+// levels, processing at each level only the blocks inside areas flagged by the previous one, or
+// the whole level when the grid of the suspect image is not aligned with the registered one.
+// Returns the grid and tampered result array of the finest level reached. Levels are checked
+// against the roots in the block header.
+async fn verify_pyramid(image_path: &str, key: &BlockKey, image_id: &str, registration: &Block, processing: &Processing<'_>, depth: usize, prefix: &str) -> Option<(BlockGrid, Vec<u32>)> {
+    let mut finest: Option<(BlockGrid, Vec<u32>)> = None;
+    // Size of the features of the suspect image, known once the coarsest level is processed whole
+    let mut suspect_size = None;
+    let levels = registration.transaction.as_ref()?.pyramid.iter().zip(&registration.header.pyramid_roots);
+    for (level, merkle_root) in levels.take(depth) {
+        let registered_tree = build_tree(level.leaves.clone(), None);
+        if registered_tree.root_hex().as_ref() != Some(merkle_root) {
+            eprintln!("Pyramid level {:?} does not match its registered merkle root", level.grid.layout.dimensions());
+            return finest;
+        }
+
+        let layout = level.grid.layout;
+        let selection = match (&finest, suspect_size) {
+            (Some((coarse, coarse_ri)), Some((width, height))) => refine_selection(coarse, coarse_ri, &level.grid, &BlockGrid::new(width, height, layout)),
+            _ => None,
+        };
+        let level_prefix = format!("{}_level{}x{}", prefix, layout.block_width, layout.block_height);
+        let processed = process_image(image_path, key, &level_image_id(image_id, layout), processing, &BlockPartition::Grid(layout), selection.as_deref(), &level_prefix)
+            .await
+            .expect("Failed to upload the pyramid blocks of the suspect image");
+        suspect_size = Some((processed.grid.width, processed.grid.height));
+        let leaves = processed.leaves;
+        let leaves = match &selection {
+            Some(selection) => merge_leaves(&level.leaves, selection, leaves),
+            None => leaves,
+        };
+
+        let mut ri = image_verification(build_tree(leaves.clone(), None), registered_tree);
+        ignore_perceptual_changes(&mut ri, &level.leaves, &leaves, processing.leaf_mode);
+        ri.truncate(level.leaves.len());
+        println!("Pyramid level {:?}: {} of {} blocks tampered", layout.dimensions(), ri.iter().filter(|&&r| r == 1).count(), ri.len());
+
+        finest = Some((level.grid, ri));
+    }
+    finest
+}
+
+// Function to restore tampered blocks once the data key is unwrapped with the master key in
+// `key_store`, or enough custodians have handed in their key shares
+async fn restore_tampered_blocks(original_image_path: &str, registration: &Transaction, key_store: &mut KeyStore, key_shares: &[KeyShare], image_id: &str, ri: &[u32]) -> Result<DynamicImage, KeySharingError> {
+    // Recover the data key before revealing any original block
+    registration.key_protection.recover(key_store, image_id, key_shares)?;
+    let extractor = registration.feature_extractor();
+
+    // Load the original image, keeping 16-bit samples
+    let original_image = load_canonical_image(original_image_path).expect("Failed to open original image");
+
+    // Block indices follow the registered partition, including its partial edge blocks
+    let regions = registration.block_regions();
+
+    // Iterate over the `ri` array
+    for (i, (&r, region)) in ri.iter().zip(&regions).enumerate() {
         if r == 1 {
-            let tx_hash = &leaves_original[i];
+            let tx_hash = registration.block_cid(i).expect("Registration has no IPFS hash for the block");
 
             // Download and decrypt the file from IPFS
             let encrypted_block = download_file_from_ipfs(tx_hash).await.expect("Failed to download from IPFS");
             
-            let decrypted_block = decrypt_block(&encrypted_block, block_size);
-            
-            // Save decrypted block for debugging
-            let file_name = format!("Decrypted_block_MSB{}.png", i + 1);
-            decrypted_block.save(&file_name).expect("Failed to save decrypted block");
-
-            // restore original format image from msb_image
-            let  original_decrypted_block = convert_msb_to_normal(&decrypted_block);
-
-            let file_name = format!("Decrypted_block_{}.png", i + 1);
-            original_decrypted_block.save(&file_name).expect("Failed to save decrypted block");
-
-            let x = (i as u32 % (width / block_size)) * block_size;
-            let y = (i as u32 / (width / block_size)) * block_size;
-
-            // Fill the block with transparent red
-            for by in 0..block_size {
-                for bx in 0..block_size {
-                    if x + bx < width && y + by < height {
-                        // let pixel = original_decrypted_block.get_pixel(bx, by);
-                        restored_image.put_pixel(x + bx, y + by, transparent_red.into());
-                    }
-                }
-            }
-        } else {
-            // Copy the block from the original image to the restored image
-            for by in 0..block_size {
-                for bx in 0..block_size {
-                    if x + bx < width && y + by < height {
-                        let pixel = original_image_buffer.get_pixel(x + bx, y + by);
-                        restored_image.put_pixel(x + bx, y + by, *pixel);
-                    }
+            match decrypt_block(&encrypted_block, key_store, image_id, i as u32, region.dimensions(), registration.block_color) {
+                Ok(decrypted_block) => {
+                    // Save decrypted block for debugging
+                    let file_name = format!("Decrypted_block_MSB{}.png", i + 1);
+                    decrypted_block.save(&file_name).expect("Failed to save decrypted block");
+
+                    // restore original format image from msb_image
+                    let original_decrypted_block = extractor.convert_to_normal(&decrypted_block);
+
+                    let file_name = format!("Decrypted_block_{}.png", i + 1);
+                    original_decrypted_block.save(&file_name).expect("Failed to save decrypted block");
                 }
+                Err(e) => eprintln!("Error decrypting block {}: {}", i + 1, e),
             }
         }
     }
 
+    // Mark the tampered blocks in transparent red
+    let restored_image = if is_16_bit(&original_image) {
+        DynamicImage::ImageRgba16(mark_tampered_blocks(&original_image.to_rgba16(), ri, &regions, Rgba([u16::MAX, 0, 0, 32768])))
+    } else {
+        DynamicImage::ImageRgba8(mark_tampered_blocks(&original_image.to_rgba8(), ri, &regions, Rgba([255, 0, 0, 128])))
+    };
+
+    Ok(restored_image)
+}
+
+// Copy the original image, filling each tampered block with `marker`
+fn mark_tampered_blocks<P: Pixel + 'static>(original_image_buffer: &ImageBuffer<P, Vec<P::Subpixel>>, ri: &[u32], regions: &[BlockRegion], marker: P) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let (width, height) = original_image_buffer.dimensions();
+    let mut restored_image = original_image_buffer.clone();
+
+    for (&r, region) in ri.iter().zip(regions) {
+        if r != 1 {
+            continue;
+        }
+
+        // Partial edge blocks are clipped to the image
+        for py in region.y..(region.y + region.height).min(height) {
+            for px in region.x..(region.x + region.width).min(width) {
+                restored_image.put_pixel(px, py, marker);
+            }
+        }
+    }
     restored_image
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block_encryption::{block_mac, block_samples};
+    use image::{GrayImage, Luma, RgbaImage};
+    use std::sync::Mutex;
+
+    const LAYOUT: BlockLayout = BlockLayout { block_width: 32, block_height: 32, padding: Padding::Zero };
+
+    fn key() -> BlockKey {
+        BlockKey::new("dek-test", [3; 16]).unwrap()
+    }
+
+    fn features() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(100, 70, |x, y| Rgba([(x * 7 % 256) as u8, (y * 13 % 256) as u8, ((x ^ y) % 256) as u8, 255])))
+    }
+
+    // Leaves computed one block after the other, as before blocks were processed in parallel
+    fn sequential_leaves(features: &DynamicImage, gray_image: Option<&DynamicImage>, partition: &BlockPartition) -> Vec<String> {
+        let gray_views: Option<Vec<BlockView>> = gray_image.map(|gray_image| block_views(gray_image, partition).collect());
+        block_views(features, partition)
+            .enumerate()
+            .map(|(i, view)| {
+                let block = view.to_image();
+                let mac = block_mac(&block_samples(&block), block.dimensions(), &key(), "image", i as u32);
+                make_leaf(gray_views.as_ref().map(|views| perceptual_hash(&views[i].to_image().to_luma8())), &mac)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parallel_blocks_give_the_sequential_leaves_and_root() {
+        let features = features();
+        let gray_image = DynamicImage::ImageLuma8(GrayImage::from_fn(100, 70, |x, y| Luma([(x * y % 256) as u8])));
+        for partition in [BlockPartition::Grid(LAYOUT), BlockPartition::Shifted(LAYOUT)] {
+            for gray_image in [None, Some(&gray_image)] {
+                let stored = Mutex::new(Vec::new());
+                let sealed = seal_blocks(&features, gray_image, &partition, &key(), "image", None, |i, _, _| stored.lock().unwrap().push(i));
+                let (indices, leaves): (Vec<usize>, Vec<String>) = sealed.into_iter().unzip();
+
+                let expected = sequential_leaves(&features, gray_image, &partition);
+                assert_eq!(indices, (0..expected.len()).collect::<Vec<_>>());
+                assert_eq!(leaves, expected);
+                assert_eq!(build_tree(leaves, None).root_hex(), build_tree(expected, None).root_hex());
+
+                let mut stored = stored.into_inner().unwrap();
+                stored.sort_unstable();
+                assert_eq!(stored, indices);
+            }
+        }
+    }
+
+    #[test]
+    fn only_selected_blocks_are_sealed() {
+        let features = features();
+        let mut selection = [false; 12];
+        selection[1] = true;
+        selection[10] = true;
+        let sealed = seal_blocks(&features, None, &BlockPartition::Grid(LAYOUT), &key(), "image", Some(&selection), |_, _, _| {});
+
+        let expected = sequential_leaves(&features, None, &BlockPartition::Grid(LAYOUT));
+        assert_eq!(sealed, [(1, expected[1].clone()), (10, expected[10].clone())]);
+    }
+
+    #[test]
+    fn blocks_past_the_selection_are_sealed() {
+        // A selection made for a registration of 6 blocks, on a suspect image of 12 blocks
+        let features = features();
+        let mut selection = [false; 6];
+        selection[1] = true;
+        let sealed = seal_blocks(&features, None, &BlockPartition::Grid(LAYOUT), &key(), "image", Some(&selection), |_, _, _| {});
+
+        let indices: Vec<usize> = sealed.iter().map(|(i, _)| *i).collect();
+        assert_eq!(indices, [1, 6, 7, 8, 9, 10, 11]);
+    }
+
+    #[test]
+    fn tampered_blocks_are_marked_on_odd_sized_images() {
+        let original = RgbaImage::from_pixel(70, 45, Rgba([0, 0, 255, 255]));
+        let grid = BlockGrid::new(70, 45, LAYOUT);
+        let marker = Rgba([255, 0, 0, 128]);
+
+        // Only the partial block of the first row is tampered
+        let ri = [0, 0, 1, 0, 0, 0];
+        let restored = mark_tampered_blocks(&original, &ri, &grid.regions(), marker);
+
+        assert_eq!(restored.dimensions(), (70, 45));
+        for (x, y, pixel) in restored.enumerate_pixels() {
+            let expected = if x >= 64 && y < 32 { marker } else { *original.get_pixel(x, y) };
+            assert_eq!(*pixel, expected, "pixel ({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn partial_blocks_of_the_last_row_are_marked() {
+        let original = RgbaImage::from_pixel(70, 45, Rgba([0, 0, 255, 255]));
+        let grid = BlockGrid::new(70, 45, BlockLayout { block_width: 32, block_height: 32, padding: Padding::Edge });
+        let marker = Rgba([255, 0, 0, 128]);
+
+        let restored = mark_tampered_blocks(&original, &[0, 0, 0, 0, 0, 1], &grid.regions(), marker);
+        assert_eq!(*restored.get_pixel(64, 32), marker);
+        assert_eq!(*restored.get_pixel(69, 44), marker);
+        assert_eq!(*restored.get_pixel(63, 44), *original.get_pixel(63, 44));
+    }
+}
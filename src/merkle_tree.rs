@@ -1,11 +1,16 @@
 // src/merkle_tree.rs
 use sha2::{Digest, Sha256};
 use std::{fmt::{self, Debug, Formatter}};
-use hex;
-use crate::Blockchain;
+use crate::blockchain::{Blockchain, Transaction};
+use serde::{Deserialize, Serialize};
+
+// Domain separation of leaf hashes, interior node hashes and committed roots
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+const ROOT_PREFIX: u8 = 0x02;
 
 #[derive(Clone)]
-struct Node {
+pub struct Node {
     hash: Vec<u8>,
     left: Option<Box<Node>>,
     right: Option<Box<Node>>,
@@ -45,45 +50,128 @@ impl Debug for Node {
 
 pub struct MerkleTree {
     pub root: Option<Node>,
+    leaves: Vec<String>, // Leaves the tree was built from, in leaf order, to issue inclusion proofs
+}
+
+// Shape of a merkle tree whose nodes mirror regions of the image, e.g. a quadtree.
+// The children of a node are combined pairwise like the leaves of a balanced tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeShape {
+    Leaf,
+    Node(Vec<TreeShape>),
+}
+
+// Proof that one leaf belongs to a merkle tree, checked with the root alone: the leaf and the
+// sibling hashes on its path to the root, O(log n) of them. Hashes are hex encoded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InclusionProof {
+    pub index: usize,
+    pub leaf_count: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<usize>, // Position of the leaf in shaped trees, see `leaf_position`
+    pub leaf: String,
+    pub siblings: Vec<String>,
+}
+
+impl InclusionProof {
+    // Check the proof against a merkle root, hex encoded as in block headers
+    pub fn verify(&self, merkle_root: &str) -> bool {
+        hex::decode(merkle_root).is_ok_and(|root| verify_proof(&root, &self.leaf, self.index, self))
+    }
+}
+
+// Hash of leaf `index`. Binding the index makes every leaf hash distinct, so that a proof is
+// only valid for the index it was issued for, whatever the shape of the tree.
+fn leaf_hash(index: usize, datum: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update((index as u64).to_be_bytes());
+    hasher.update(datum);
+    hasher.finalize().to_vec()
+}
+
+fn node_hash(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().to_vec()
+}
+
+// Root recorded on chain: the hash of the top node together with the number of leaves, so that
+// trees of different sizes never share a root
+fn committed_root(leaf_count: usize, top_hash: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update([ROOT_PREFIX]);
+    hasher.update((leaf_count as u64).to_be_bytes());
+    hasher.update(top_hash);
+    hasher.finalize().to_vec()
+}
+
+// Position of leaf `index` in a balanced tree of `leaf_count` leaves, see `leaf_position`.
+// The last node of an odd level is promoted without a sibling, as in RFC 6962, so levels where
+// the leaf has no sibling add no bit.
+fn balanced_position(index: usize, leaf_count: usize) -> usize {
+    let (mut index, mut last) = (index, leaf_count.saturating_sub(1));
+    let (mut position, mut height) = (0, 0);
+    while last > 0 {
+        if index % 2 == 1 {
+            position |= 1 << height;
+            height += 1;
+        } else if index < last {
+            height += 1;
+        }
+        index /= 2;
+        last /= 2;
+    }
+    position
 }
 
 impl MerkleTree {
     pub fn new(data: Vec<&str>) -> Self {
-        let leaves: Vec<Node> = data
-            .into_iter()
-            .map(|datum| {
-                let mut hasher = Sha256::new();
-                hasher.update(datum);
-                let hash = hasher.finalize().to_vec();
-                Node::new(hash, 1) // Each leaf node has 1 leaf
-            })
-            .collect();
+        let root = Self::build_tree(Self::leaf_nodes(&data));
 
-        let root = Self::build_tree(leaves);
+        MerkleTree { root, leaves: data.into_iter().map(str::to_string).collect() }
+    }
+
+    // Merkle tree of the leaves, in leaf order, grouped by `shape`
+    pub fn with_shape(data: Vec<&str>, shape: &TreeShape) -> Self {
+        let root = Self::build_shape(shape, &mut Self::leaf_nodes(&data).into_iter());
 
-        MerkleTree { root }
+        MerkleTree { root, leaves: data.into_iter().map(str::to_string).collect() }
     }
 
+    fn leaf_nodes(data: &[&str]) -> Vec<Node> {
+        data.iter()
+            .enumerate()
+            .map(|(index, datum)| Node::new(leaf_hash(index, datum), 1)) // Each leaf node has 1 leaf
+            .collect()
+    }
+
+    fn build_shape(shape: &TreeShape, leaves: &mut dyn Iterator<Item = Node>) -> Option<Node> {
+        match shape {
+            TreeShape::Leaf => leaves.next(),
+            TreeShape::Node(children) => Self::build_tree(children.iter().filter_map(|child| Self::build_shape(child, leaves)).collect()),
+        }
+    }
+
+    // The last node of an odd level is promoted to the next level as it is
     fn build_tree(mut nodes: Vec<Node>) -> Option<Node> {
         while nodes.len() > 1 {
             let mut next_level = Vec::new();
 
             for chunk in nodes.chunks(2) {
-                let left = chunk[0].clone();
-                let right = if chunk.len() == 2 { chunk[1].clone() } else { chunk[0].clone() };
-
-                let mut hasher = Sha256::new();
-                hasher.update(&left.hash);
-                hasher.update(&right.hash);
-                let parent_hash = hasher.finalize().to_vec();
-
-                let len = next_level.len() + 1 ;
+                let [left, right] = chunk else {
+                    next_level.push(chunk[0].clone());
+                    continue;
+                };
 
+                let parent_hash = node_hash(&left.hash, &right.hash);
                 let num_leaves = left.num_leaves+right.num_leaves; // Sum of leaves under the left and right nodes
 
                 let mut parent_node = Node::new(parent_hash, num_leaves);
-                parent_node.left = Some(Box::new(left));
-                parent_node.right = Some(Box::new(right));
+                parent_node.left = Some(Box::new(left.clone()));
+                parent_node.right = Some(Box::new(right.clone()));
 
                 next_level.push(parent_node);
             }
@@ -118,8 +206,9 @@ impl MerkleTree {
         }
     }
 
+    // Root committing to the leaves and their number, as recorded in block headers
     pub fn root_hex(&self) -> Option<String> {
-        self.root.as_ref().map(|node| hex::encode(&node.hash))
+        self.root.as_ref().map(|node| hex::encode(committed_root(self.leaves.len(), &node.hash)))
     }
 
     pub fn size(&self) -> usize {
@@ -127,6 +216,71 @@ impl MerkleTree {
         self.traverse(&mut |_| count += 1);
         count
     }
+
+    // Proof that leaf `index` belongs to the tree, or None past the last leaf. Shaped trees carry
+    // the position of the leaf, which balanced trees derive from the index and the leaf count.
+    pub fn prove(&self, index: usize) -> Option<InclusionProof> {
+        let (siblings, position) = self.audit_path(index)?;
+        let leaf_count = self.leaves.len();
+        Some(InclusionProof {
+            index,
+            leaf_count,
+            position: (position != balanced_position(index, leaf_count)).then_some(position),
+            leaf: self.leaves[index].clone(),
+            siblings: siblings.iter().map(hex::encode).collect(),
+        })
+    }
+
+    // Sibling hashes on the path from leaf `index` to the root, ordered from the leaf upwards, and
+    // the position of the leaf: bit i is set when the leaf is under the right child of the i-th
+    // node from the leaf that has two children
+    fn audit_path(&self, index: usize) -> Option<(Vec<Vec<u8>>, usize)> {
+        if index >= self.leaves.len() {
+            return None;
+        }
+
+        // The leaf counts tell which child the leaf lives under
+        let mut node = self.root.as_ref()?;
+        let mut index = index;
+        let mut proof = Vec::new();
+        let mut sides = Vec::new();
+        while let (Some(left), Some(right)) = (&node.left, &node.right) {
+            if index < left.num_leaves {
+                proof.push(right.hash.clone());
+                sides.push(false);
+                node = left;
+            } else {
+                proof.push(left.hash.clone());
+                sides.push(true);
+                index -= left.num_leaves;
+                node = right;
+            }
+        }
+
+        proof.reverse();
+        let position = sides.iter().rev().enumerate().filter(|(_, &right)| right).map(|(height, _)| 1 << height).sum();
+        Some((proof, position))
+    }
+}
+
+// Check that `leaf` is leaf `index` of the tree with the given root, from the sibling hashes of
+// `proof`. The proof must have been issued for that leaf and index.
+pub fn verify_proof(root: &[u8], leaf: &str, index: usize, proof: &InclusionProof) -> bool {
+    if proof.leaf != leaf || proof.index != index || index >= proof.leaf_count {
+        return false;
+    }
+    let Ok(siblings) = proof.siblings.iter().map(hex::decode).collect::<Result<Vec<_>, _>>() else {
+        return false;
+    };
+
+    let mut hash = leaf_hash(index, leaf);
+    let mut position = proof.position.unwrap_or_else(|| balanced_position(index, proof.leaf_count));
+    for sibling in &siblings {
+        hash = if position.is_multiple_of(2) { node_hash(&hash, sibling) } else { node_hash(sibling, &hash) };
+        position /= 2;
+    }
+
+    position == 0 && committed_root(proof.leaf_count, &hash) == root
 }
 
 //-------------------------------------------------------------------- MERKLE TREE COMPARISON: START --------------------------------------------------------------------
@@ -170,26 +324,142 @@ fn compare_nodes_merkle(node1: &Node, node2: &Node, result: &mut Vec<u32>) {
 //-------------------------------------------------------------------- MERKLE TREE COMPARISON: END --------------------------------------------------------------------
 
 
-pub fn insert_root(leaves_original: Vec<String>, blockchain: &mut Blockchain) {
-    let leaves_as_str_original: Vec<&str> = leaves_original.iter().map(|s| s.as_str()).collect();
-    let merkle_tree = MerkleTree::new(leaves_as_str_original.clone());
+// Build the merkle tree of a registration's leaves and record it in the blockchain
+pub fn insert_root(transaction: Transaction, blockchain: &mut Blockchain) {
+    let merkle_tree = build_tree(transaction.tx.clone(), transaction.tree_shape().as_ref());
 
     match merkle_tree.root_hex() {
         Some(root) => {
             println!("This is the root node: {:?}", root);
+            println!("Merkle tree size: {}", merkle_tree.size());
             merkle_tree.print_tree();
             merkle_tree.traverse(&mut |node| println!("{}", hex::encode(&node.hash)));
 
-            blockchain.add_block(root, leaves_original)
+            blockchain.add_block(root, transaction)
         }
         None => eprintln!("Couldn't get the merkle root"),
     }
 }
 
-pub fn build_tree(leaves_original: Vec<String>) -> MerkleTree {
+// Inclusion proof of block `index` of a registration, to be checked against its on-chain merkle root
+pub fn prove_block(transaction: &Transaction, index: usize) -> Option<InclusionProof> {
+    build_tree(transaction.tx.clone(), transaction.tree_shape().as_ref()).prove(index)
+}
+
+// Balanced merkle tree of the leaves, or grouped by `shape` for adaptive partitions
+pub fn build_tree(leaves_original: Vec<String>, shape: Option<&TreeShape>) -> MerkleTree {
     let leaves_as_str_original: Vec<&str> = leaves_original.iter().map(|s| s.as_str()).collect();
-    let merkle_tree = MerkleTree::new(leaves_as_str_original.clone());
-    merkle_tree
+    match shape {
+        Some(shape) => MerkleTree::with_shape(leaves_as_str_original, shape),
+        None => MerkleTree::new(leaves_as_str_original),
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::test_registration;
+    use crate::image_to_chunks::{BlockGrid, BlockLayout, Padding};
+
+    fn leaves(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("leaf {}", i)).collect()
+    }
+
+    #[test]
+    fn every_leaf_has_a_valid_proof() {
+        for count in 1..=9 {
+            let leaves = leaves(count);
+            let tree = build_tree(leaves.clone(), None);
+            let root = tree.root_hex().unwrap();
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = tree.prove(index).unwrap();
+                assert_eq!(proof.position, None);
+                assert_eq!(proof.leaf, *leaf);
+                assert!(proof.verify(&root), "leaf {} of {}", index, count);
+                assert!(verify_proof(&hex::decode(&root).unwrap(), leaf, index, &proof));
+            }
+            assert!(tree.prove(count).is_none());
+        }
+    }
+
+    #[test]
+    fn proofs_have_logarithmic_length() {
+        let leaves = leaves(1000);
+        let tree = build_tree(leaves.clone(), None);
+        assert!((0..1000).all(|index| tree.prove(index).unwrap().siblings.len() <= 10));
+    }
+
+    #[test]
+    fn proofs_fail_for_other_leaves_and_positions() {
+        let leaves = leaves(6);
+        let tree = build_tree(leaves.clone(), None);
+        let root = tree.root_hex().unwrap();
+        let proof = tree.prove(2).unwrap();
+
+        let root_bytes = hex::decode(&root).unwrap();
+        assert!(!verify_proof(&root_bytes, &leaves[3], 2, &proof));
+        assert!(!verify_proof(&root_bytes, &leaves[2], 3, &proof));
+        assert!(!InclusionProof { leaf: "tampered".to_string(), ..proof.clone() }.verify(&root));
+        assert!(!InclusionProof { index: 3, ..proof.clone() }.verify(&root));
+        assert!(!InclusionProof { index: 2 + 8, ..proof.clone() }.verify(&root));
+        assert!(!proof.verify(&build_tree(leaves[1..].to_vec(), None).root_hex().unwrap()));
+        assert!(!proof.verify("not hex"));
+    }
+
+    #[test]
+    fn last_leaf_of_an_odd_tree_only_verifies_at_its_index() {
+        let leaves = leaves(5);
+        let tree = build_tree(leaves.clone(), None);
+        let root = tree.root_hex().unwrap();
+        let proof = tree.prove(4).unwrap();
+        assert!(proof.verify(&root));
+
+        // Indices past the last leaf, whether the leaf count is kept or raised to include them
+        for index in 5..8 {
+            assert!(!InclusionProof { index, ..proof.clone() }.verify(&root), "index {}", index);
+            assert!(!InclusionProof { index, leaf_count: 8, ..proof.clone() }.verify(&root), "index {} of 8", index);
+        }
+
+        // Nor with an explicit position, which does not override the index
+        for position in 0..8 {
+            assert!(!InclusionProof { index: 5, position: Some(position), ..proof.clone() }.verify(&root));
+        }
+    }
+
+    #[test]
+    fn duplicating_the_last_leaf_changes_the_root() {
+        let abc = build_tree(vec!["a".to_string(), "b".to_string(), "c".to_string()], None);
+        let abcc = build_tree(vec!["a".to_string(), "b".to_string(), "c".to_string(), "c".to_string()], None);
+        assert_ne!(abc.root_hex(), abcc.root_hex());
 
+        // A proof of [a, b, c] does not verify as a proof of a 4 leaf tree
+        let proof = abc.prove(2).unwrap();
+        assert!(!InclusionProof { leaf_count: 4, ..proof.clone() }.verify(&abc.root_hex().unwrap()));
+        assert!(!InclusionProof { index: 3, leaf_count: 4, ..proof }.verify(&abcc.root_hex().unwrap()));
+    }
+
+    #[test]
+    fn proofs_are_bound_to_the_leaf_count() {
+        let leaves = leaves(6);
+        let tree = build_tree(leaves.clone(), None);
+        let root = tree.root_hex().unwrap();
+        let proof = tree.prove(1).unwrap();
+
+        for leaf_count in [0, 1, 5, 7, 8] {
+            assert!(!InclusionProof { leaf_count, ..proof.clone() }.verify(&root), "{} leaves", leaf_count);
+        }
+    }
+
+    #[test]
+    fn registered_blocks_are_proven_against_the_chain() {
+        let mut blockchain = Blockchain::new();
+        insert_root(test_registration(BlockGrid::new(80, 16, BlockLayout { block_width: 16, block_height: 16, padding: Padding::Zero }), leaves(5), Vec::new()), &mut blockchain);
+        let block = blockchain.chain.last().unwrap();
+
+        let proof = prove_block(block.transaction.as_ref().unwrap(), 4).unwrap();
+        let proof: InclusionProof = serde_json::from_str(&serde_json::to_string(&proof).unwrap()).unwrap();
+        assert!(proof.verify(&block.header.merkle_root));
+        assert_eq!(proof.leaf_count, 5);
+        assert!(prove_block(block.transaction.as_ref().unwrap(), 5).is_none());
+    }
+}
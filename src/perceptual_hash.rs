@@ -0,0 +1,110 @@
+// src/perceptual_hash.rs
+
+use crate::dct::dct_2d;
+use image::imageops::{self, FilterType};
+use image::GrayImage;
+
+// Size the block is resampled to before the DCT
+const HASH_INPUT_SIZE: u32 = 32;
+
+// Number of low frequency coefficients kept per axis, giving 63 AC coefficients
+const HASH_FREQUENCIES: usize = 8;
+
+// How a block is fingerprinted in the leaves of the merkle tree. Recorded in the registration,
+// so that suspect images are compared with the registered threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeafMode {
+    // The keyed MAC of the MSB block: any change of the MSBs is reported
+    Exact,
+    // The MAC prefixed by a DCT perceptual hash of the block. Blocks whose perceptual
+    // hashes differ by at most `max_distance` bits are not reported, so recompression is tolerated.
+    // Independent of the feature extractor, including `BlurredLumaOptions`.
+    Perceptual { max_distance: u32 },
+}
+
+// DCT-based perceptual hash of a block: bit i tells whether the (i + 1)-th low frequency
+// coefficient is above the median of the AC coefficients, giving 63 bits
+pub fn perceptual_hash(block: &GrayImage) -> u64 {
+    let resized = imageops::resize(block, HASH_INPUT_SIZE, HASH_INPUT_SIZE, FilterType::Triangle);
+    let samples: Vec<f32> = resized.pixels().map(|pixel| pixel[0] as f32).collect();
+    let coefficients = dct_2d(&samples, HASH_INPUT_SIZE as usize, HASH_FREQUENCIES);
+
+    // The DC coefficient only carries the mean brightness and is left out. There is an odd
+    // number of AC coefficients, so the median is the middle one.
+    let ac = &coefficients[1..];
+    let mut sorted = ac.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let median = sorted[sorted.len() / 2];
+
+    ac.iter()
+        .enumerate()
+        .filter(|(_, &coefficient)| coefficient > median)
+        .fold(0u64, |hash, (bit, _)| hash | 1 << bit)
+}
+
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+// Leaf of a block: the MAC of its features, prefixed by its perceptual hash in perceptual mode
+pub fn make_leaf(perceptual_hash: Option<u64>, mac: &str) -> String {
+    match perceptual_hash {
+        Some(hash) => format!("{:016x}:{}", hash, mac),
+        None => mac.to_string(),
+    }
+}
+
+// Split a leaf into its perceptual hash, if any, and its MAC
+pub fn split_leaf(leaf: &str) -> (Option<u64>, &str) {
+    match leaf.split_once(':') {
+        Some((hash, mac)) => match u64::from_str_radix(hash, 16) {
+            Ok(hash) => (Some(hash), mac),
+            Err(_) => (None, leaf),
+        },
+        None => (None, leaf),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    fn textured_block() -> GrayImage {
+        GrayImage::from_fn(32, 32, |x, y| Luma([((x * 37 + y * 91 + x * y) % 251) as u8]))
+    }
+
+    #[test]
+    fn half_of_the_ac_coefficients_are_above_the_median() {
+        // 31 of the 63 AC coefficients are above the middle one, and no bit is left for the DC
+        let hash = perceptual_hash(&textured_block());
+        assert_eq!(hash.count_ones(), 31);
+        assert_eq!(hash >> 63, 0);
+    }
+
+    #[test]
+    fn brightness_does_not_change_the_hash() {
+        // Only the DC coefficient changes when every pixel is brightened
+        let block = textured_block();
+        let brighter = GrayImage::from_fn(32, 32, |x, y| Luma([block.get_pixel(x, y)[0] / 2 + 100]));
+        let darker = GrayImage::from_fn(32, 32, |x, y| Luma([block.get_pixel(x, y)[0] / 2]));
+        assert_eq!(perceptual_hash(&brighter), perceptual_hash(&darker));
+    }
+
+    #[test]
+    fn small_changes_stay_within_the_threshold() {
+        let block = textured_block();
+        let noisy = GrayImage::from_fn(32, 32, |x, y| Luma([block.get_pixel(x, y)[0].saturating_add(((x + y) % 3) as u8)]));
+        let other = GrayImage::from_fn(32, 32, |x, y| Luma([((x * 5 + y * 200) % 256) as u8]));
+
+        let hash = perceptual_hash(&block);
+        assert!(hamming_distance(hash, perceptual_hash(&noisy)) <= 10);
+        assert!(hamming_distance(hash, perceptual_hash(&other)) > 10);
+    }
+
+    #[test]
+    fn leaves_split_into_their_parts() {
+        assert_eq!(split_leaf(&make_leaf(Some(0xabc), "mac")), (Some(0xabc), "mac"));
+        assert_eq!(split_leaf(&make_leaf(None, "mac")), (None, "mac"));
+    }
+}
@@ -0,0 +1,110 @@
+// src/pyramid.rs
+
+use crate::image_to_chunks::{BlockGrid, BlockLayout};
+
+// Leaves of the image at one block size of a multi-resolution registration. Verifiers
+// compare the coarsest level first and only process the finer blocks of flagged areas.
+// The merkle root of each level is recorded in the block header.
+#[derive(Debug, Clone)]
+pub struct PyramidLevel {
+    pub grid: BlockGrid,
+    pub leaves: Vec<String>,
+    pub cids: Vec<String>, // IPFS hashes of the encrypted blocks, in leaf order
+}
+
+// Image id the blocks of a level are encrypted under. Each level gets its own block and MAC keys
+// and associated data, so blocks and leaves cannot be moved from one level to another.
+pub fn level_image_id(image_id: &str, layout: BlockLayout) -> String {
+    format!("{}@{}x{}", image_id, layout.block_width, layout.block_height)
+}
+
+// Blocks of the registered `fine` grid that overlap a tampered block of the `coarse` grid.
+// The selection only refers to the same blocks of the suspect image when its grid at this level
+// is aligned with the registered one; otherwise the whole level must be processed and None is returned.
+pub fn refine_selection(coarse: &BlockGrid, coarse_ri: &[u32], fine: &BlockGrid, suspect: &BlockGrid) -> Option<Vec<bool>> {
+    if !suspect.is_aligned_with(fine) {
+        return None;
+    }
+    let flagged: Vec<_> = coarse.regions().into_iter().zip(coarse_ri).filter(|(_, &r)| r == 1).map(|(region, _)| region).collect();
+    Some(fine.regions().iter().map(|region| flagged.iter().any(|tampered| tampered.overlaps(region))).collect())
+}
+
+// Leaves of a suspect image of which only the selected blocks were processed, in order.
+// The other blocks take the registered leaves, as the coarser level found them untampered.
+pub fn merge_leaves(registered: &[String], selection: &[bool], processed: Vec<String>) -> Vec<String> {
+    let mut processed = processed.into_iter();
+    registered
+        .iter()
+        .zip(selection)
+        .map(|(leaf, &selected)| if selected { processed.next().unwrap_or_default() } else { leaf.clone() })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{calculate_hash, test_registration, Blockchain, Transaction};
+    use crate::image_to_chunks::Padding;
+    use crate::merkle_tree::build_tree;
+
+    fn grid(size: u32) -> BlockGrid {
+        BlockGrid::new(100, 70, BlockLayout { block_width: size, block_height: size, padding: Padding::Zero })
+    }
+
+    #[test]
+    fn finer_blocks_inside_flagged_areas_are_selected() {
+        // 64x64 grid of 2x2 blocks: the top right block covers x 64..128, y 0..64
+        let (coarse, fine) = (grid(64), grid(32));
+        let selection = refine_selection(&coarse, &[0, 1, 0, 0], &fine, &fine).unwrap();
+
+        // 32x32 grid of 4x3 blocks: the top right block covers columns 2 and 3 of rows 0 and 1
+        let selected: Vec<usize> = selection.iter().enumerate().filter(|(_, &selected)| selected).map(|(i, _)| i).collect();
+        assert_eq!(selected, [2, 3, 6, 7]);
+    }
+
+    #[test]
+    fn untampered_images_select_nothing() {
+        assert!(refine_selection(&grid(64), &[0, 0, 0, 0], &grid(16), &grid(16)).unwrap().iter().all(|&selected| !selected));
+    }
+
+    #[test]
+    fn larger_suspect_images_process_the_whole_level() {
+        // A suspect padded to 130x70 has an extra column of 32x32 blocks, and as many rows
+        let (coarse, fine) = (grid(64), grid(32));
+        let suspect = BlockGrid::new(130, 70, fine.layout);
+        assert_eq!(refine_selection(&coarse, &[0, 1, 0, 0], &fine, &suspect), None);
+
+        // Smaller suspect images have fewer rows
+        let suspect = BlockGrid::new(100, 60, fine.layout);
+        assert_eq!(refine_selection(&coarse, &[0, 1, 0, 0], &fine, &suspect), None);
+    }
+
+    #[test]
+    fn unselected_leaves_are_taken_from_the_registration() {
+        let registered: Vec<String> = ["a", "b", "c", "d"].iter().map(|leaf| leaf.to_string()).collect();
+        let merged = merge_leaves(&registered, &[false, true, false, true], vec!["x".to_string(), "y".to_string()]);
+        assert_eq!(merged, ["a", "x", "c", "y"]);
+    }
+
+    #[test]
+    fn level_roots_are_committed_in_the_header() {
+        let leaves: Vec<String> = ["a", "b", "c"].iter().map(|leaf| leaf.to_string()).collect();
+        let level = PyramidLevel { grid: grid(64), leaves: leaves.clone(), cids: Vec::new() };
+        let mut blockchain = Blockchain::new();
+        blockchain.add_block("root".to_string(), Transaction { pyramid: vec![level], ..test_registration(grid(16), Vec::new(), Vec::new()) });
+
+        let header = &blockchain.chain.last().unwrap().header;
+        assert_eq!(header.pyramid_roots, [build_tree(leaves, None).root_hex().unwrap()]);
+
+        // Replacing the level leaves and their root changes the block hash
+        let mut forged = header.clone();
+        forged.pyramid_roots = vec![build_tree(vec!["x".to_string()], None).root_hex().unwrap()];
+        assert_ne!(calculate_hash(&forged), calculate_hash(header));
+    }
+
+    #[test]
+    fn levels_have_distinct_image_ids() {
+        let (coarse, fine) = (grid(64).layout, grid(32).layout);
+        assert_ne!(level_image_id("image", coarse), level_image_id("image", fine));
+    }
+}
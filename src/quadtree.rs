@@ -0,0 +1,197 @@
+// src/quadtree.rs
+
+use crate::image_to_chunks::{BlockGrid, BlockLayout, BlockRegion};
+use crate::merkle_tree::TreeShape;
+use image::GrayImage;
+
+// Parameters of the adaptive partitioning. Blocks start at the size of the layout and are
+// split into four while their content is detailed, so flat areas such as sky use few leaves
+// and detailed areas such as faces are localized finely.
+#[derive(Debug, Clone, Copy)]
+pub struct QuadtreeOptions {
+    pub min_block_size: u32, // Blocks are not split below this width or height
+    pub max_deviation: f32,  // Blocks whose luminance standard deviation is above this are split
+}
+
+// Quadtrees over the blocks of a grid, as chosen on the registered image. The suspect image
+// is sliced along the same partition so that leaves correspond.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuadtreePartition {
+    pub roots: BlockGrid,     // Grid of the largest blocks, each the root of a quadtree
+    pub min_block_size: u32,
+    pub splits: Vec<bool>, // Whether each splittable node is split, depth first
+}
+
+impl QuadtreePartition {
+    // Partition the grid of `layout` blocks over the luminance of an image
+    pub fn build(luma: &GrayImage, layout: BlockLayout, options: QuadtreeOptions) -> Self {
+        let roots = BlockGrid::new(luma.width(), luma.height(), layout);
+        let mut partition = QuadtreePartition { roots, min_block_size: options.min_block_size, splits: Vec::new() };
+
+        for root in roots.regions() {
+            partition.split_region(luma, root, options.max_deviation);
+        }
+        partition
+    }
+
+    fn split_region(&mut self, luma: &GrayImage, region: BlockRegion, max_deviation: f32) {
+        if !self.is_splittable(region) {
+            return;
+        }
+        let split = luminance_deviation(luma, region) > max_deviation;
+        self.splits.push(split);
+        if split {
+            for child in self.children(region) {
+                self.split_region(luma, child, max_deviation);
+            }
+        }
+    }
+
+    fn is_splittable(&self, region: BlockRegion) -> bool {
+        region.width.is_multiple_of(2)
+            && region.height.is_multiple_of(2)
+            && region.width / 2 >= self.min_block_size
+            && region.height / 2 >= self.min_block_size
+    }
+
+    // Quadrants of a region in reading order. Quadrants entirely outside the image have no block.
+    fn children(&self, region: BlockRegion) -> Vec<BlockRegion> {
+        let (width, height) = (region.width / 2, region.height / 2);
+        [(0, 0), (width, 0), (0, height), (width, height)]
+            .into_iter()
+            .map(|(dx, dy)| BlockRegion { x: region.x + dx, y: region.y + dy, width, height })
+            .filter(|child| child.x < self.roots.width && child.y < self.roots.height)
+            .collect()
+    }
+
+    // Regions of the blocks, root by root and depth first within each root
+    pub fn leaves(&self) -> Vec<BlockRegion> {
+        let mut leaves = Vec::new();
+        self.shape_with(&mut |region| leaves.push(region));
+        leaves
+    }
+
+    // Shape of the merkle tree: one node per quadtree node, over a balanced tree of the roots
+    pub fn tree_shape(&self) -> TreeShape {
+        self.shape_with(&mut |_| {})
+    }
+
+    // Replay the recorded splits, calling `leaf` on each block in leaf order
+    fn shape_with(&self, leaf: &mut dyn FnMut(BlockRegion)) -> TreeShape {
+        let mut splits = self.splits.iter().copied();
+        let roots = self.roots.regions().into_iter().map(|root| self.node_shape(root, &mut splits, leaf)).collect();
+        TreeShape::Node(roots)
+    }
+
+    fn node_shape(&self, region: BlockRegion, splits: &mut dyn Iterator<Item = bool>, leaf: &mut dyn FnMut(BlockRegion)) -> TreeShape {
+        if self.is_splittable(region) && splits.next().unwrap_or(false) {
+            TreeShape::Node(self.children(region).into_iter().map(|child| self.node_shape(child, splits, leaf)).collect())
+        } else {
+            leaf(region);
+            TreeShape::Leaf
+        }
+    }
+}
+
+// Standard deviation of the luminance over the part of a region inside the image
+fn luminance_deviation(luma: &GrayImage, region: BlockRegion) -> f32 {
+    let (x_end, y_end) = ((region.x + region.width).min(luma.width()), (region.y + region.height).min(luma.height()));
+    let (mut count, mut sum, mut sum_squares) = (0u64, 0u64, 0u64);
+    for y in region.y..y_end {
+        for x in region.x..x_end {
+            let value = luma.get_pixel(x, y)[0] as u64;
+            count += 1;
+            sum += value;
+            sum_squares += value * value;
+        }
+    }
+
+    if count == 0 {
+        return 0.0;
+    }
+    let mean = sum as f64 / count as f64;
+    (sum_squares as f64 / count as f64 - mean * mean).max(0.0).sqrt() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_to_chunks::Padding;
+    use crate::merkle_tree::{build_tree, compare_merkle_trees, InclusionProof};
+    use image::Luma;
+
+    const LAYOUT: BlockLayout = BlockLayout { block_width: 32, block_height: 32, padding: Padding::Zero };
+    const OPTIONS: QuadtreeOptions = QuadtreeOptions { min_block_size: 8, max_deviation: 20.0 };
+
+    // Flat 100x70 image whose first 32x32 block is detailed
+    fn patched_image() -> GrayImage {
+        GrayImage::from_fn(100, 70, |x, y| Luma([if x < 32 && y < 32 { ((x * 37 + y * 91) % 255) as u8 } else { 100 }]))
+    }
+
+    #[test]
+    fn flat_images_keep_the_grid() {
+        let partition = QuadtreePartition::build(&GrayImage::from_pixel(100, 70, Luma([100])), LAYOUT, OPTIONS);
+        assert_eq!(partition.leaves(), partition.roots.regions());
+    }
+
+    #[test]
+    fn detailed_areas_are_split_down_to_the_minimum_size() {
+        let partition = QuadtreePartition::build(&patched_image(), LAYOUT, OPTIONS);
+        let leaves = partition.leaves();
+
+        // The first root is split into 16 blocks of 8x8, the 11 other roots are kept
+        assert_eq!(leaves.len(), 16 + 11);
+        assert!(leaves[..16].iter().all(|leaf| leaf.dimensions() == (8, 8)));
+        assert_eq!(leaves[16], BlockRegion { x: 32, y: 0, width: 32, height: 32 });
+    }
+
+    #[test]
+    fn leaves_cover_every_pixel_once() {
+        let image = patched_image();
+        let partition = QuadtreePartition::build(&image, LAYOUT, OPTIONS);
+
+        let mut coverage = vec![0; (image.width() * image.height()) as usize];
+        for leaf in partition.leaves() {
+            for y in leaf.y..(leaf.y + leaf.height).min(image.height()) {
+                for x in leaf.x..(leaf.x + leaf.width).min(image.width()) {
+                    coverage[(y * image.width() + x) as usize] += 1;
+                }
+            }
+        }
+        assert!(coverage.iter().all(|&count| count == 1));
+    }
+
+    #[test]
+    fn quadrants_outside_the_image_have_no_block() {
+        // The 40x40 root of a 50x20 image only has its top quadrants inside the image
+        let image = GrayImage::from_fn(50, 20, |x, y| Luma([((x * 37 + y * 91) % 255) as u8]));
+        let layout = BlockLayout { block_width: 40, block_height: 40, ..LAYOUT };
+        let partition = QuadtreePartition::build(&image, layout, QuadtreeOptions { min_block_size: 20, ..OPTIONS });
+
+        let leaves = partition.leaves();
+        assert_eq!(leaves[..2], [BlockRegion { x: 0, y: 0, width: 20, height: 20 }, BlockRegion { x: 20, y: 0, width: 20, height: 20 }]);
+        assert_eq!(leaves.len(), 3);
+    }
+
+    #[test]
+    fn shaped_merkle_tree_localizes_and_proves_leaves() {
+        let partition = QuadtreePartition::build(&patched_image(), LAYOUT, OPTIONS);
+        let shape = partition.tree_shape();
+        let leaves: Vec<String> = (0..partition.leaves().len()).map(|i| format!("leaf {}", i)).collect();
+
+        let tree = build_tree(leaves.clone(), Some(&shape));
+        let root = tree.root_hex().unwrap();
+        for (index, leaf) in leaves.iter().enumerate() {
+            let proof = tree.prove(index).unwrap();
+            assert_eq!(&proof.leaf, leaf);
+            assert!(proof.verify(&root));
+            assert!(!InclusionProof { index: (index + 1) % leaves.len(), ..proof }.verify(&root));
+        }
+
+        let mut tampered = leaves.clone();
+        tampered[5] = "tampered".to_string();
+        let ri = compare_merkle_trees(&tree, &build_tree(tampered, Some(&shape)));
+        assert_eq!(ri.iter().position(|&r| r == 1), Some(5));
+        assert_eq!(ri.iter().filter(|&&r| r == 1).count(), 1);
+    }
+}
@@ -0,0 +1,153 @@
+// src/shifted_grid.rs
+
+use crate::image_to_chunks::{BlockGrid, BlockLayout, BlockRegion};
+
+// Leaves of the image on a second grid offset by half a block. An edit aligned with the block
+// boundaries of one grid straddles blocks of the other, and intersecting the tampered blocks
+// of both grids localizes tampering to quarter blocks. The merkle root of the shifted grid is
+// recorded in the block header.
+#[derive(Debug, Clone)]
+pub struct ShiftedGrid {
+    pub leaves: Vec<String>,
+    pub cids: Vec<String>, // IPFS hashes of the encrypted blocks, in leaf order
+}
+
+// Image id the blocks of the shifted grid are encrypted under. The shifted grid gets its own block
+// and MAC keys and associated data, so its blocks and leaves cannot be moved to the main grid.
+pub fn shifted_image_id(image_id: &str, layout: BlockLayout) -> String {
+    let (dx, dy) = layout.half_block();
+    format!("{}@{}x{}+{}+{}", image_id, layout.block_width, layout.block_height, dx, dy)
+}
+
+// Areas of the image whose block is tampered in both the main grid and the shifted grid, as
+// the cells between the block boundaries of both grids. Cells not covered by the shifted grid,
+// along the top and left edges, only depend on the main grid.
+pub fn localize_tampering(grid: &BlockGrid, ri: &[u32], shifted_ri: &[u32]) -> Vec<BlockRegion> {
+    let layout = grid.layout;
+    let (dx, dy) = layout.half_block();
+    let shifted_columns = grid.width.saturating_sub(dx).div_ceil(layout.block_width);
+
+    let columns = cell_boundaries(grid.width, layout.block_width, dx);
+    let rows = cell_boundaries(grid.height, layout.block_height, dy);
+
+    let mut tampered = Vec::new();
+    for cell_rows in rows.windows(2) {
+        for cell_columns in columns.windows(2) {
+            let (x, y) = (cell_columns[0], cell_rows[0]);
+            let index = (y / layout.block_height * grid.columns() + x / layout.block_width) as usize;
+            if ri.get(index) != Some(&1) {
+                continue;
+            }
+
+            let shifted_flagged = if x < dx || y < dy {
+                true
+            } else {
+                let shifted_index = ((y - dy) / layout.block_height * shifted_columns + (x - dx) / layout.block_width) as usize;
+                shifted_ri.get(shifted_index) == Some(&1)
+            };
+            if shifted_flagged {
+                tampered.push(BlockRegion { x, y, width: cell_columns[1] - x, height: cell_rows[1] - y });
+            }
+        }
+    }
+    tampered
+}
+
+// Sorted positions where a block of either grid starts along one axis, and the image end
+fn cell_boundaries(length: u32, block_size: u32, offset: u32) -> Vec<u32> {
+    let mut boundaries: Vec<u32> = (0..length).step_by(block_size as usize).chain((offset..length).step_by(block_size as usize)).collect();
+    boundaries.push(length);
+    boundaries.sort_unstable();
+    boundaries.dedup();
+    boundaries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{calculate_hash, test_registration, Blockchain, Transaction};
+    use crate::image_to_chunks::{BlockPartition, Padding};
+    use crate::merkle_tree::build_tree;
+
+    const LAYOUT: BlockLayout = BlockLayout { block_width: 32, block_height: 32, padding: Padding::Zero };
+
+    // 100x70 image: main grid of 4x3 blocks, shifted grid of 3x2 blocks starting at (16, 16)
+    fn grid() -> BlockGrid {
+        BlockGrid::new(100, 70, LAYOUT)
+    }
+
+    #[test]
+    fn shifted_blocks_are_offset_by_half_a_block() {
+        let regions = BlockPartition::Shifted(LAYOUT).regions(100, 70);
+        assert_eq!(regions.len(), 6);
+        assert_eq!(regions[0], BlockRegion { x: 16, y: 16, width: 32, height: 32 });
+        assert_eq!(regions[5], BlockRegion { x: 80, y: 48, width: 32, height: 32 });
+    }
+
+    #[test]
+    fn intersection_localizes_tampering_to_a_quarter_block() {
+        // On a 128x128 image, tampering at (72, 40) lies in main block 6 and shifted block 1
+        let grid = BlockGrid::new(128, 128, LAYOUT);
+        let mut ri = vec![0; 16];
+        ri[6] = 1;
+        let mut shifted_ri = vec![0; 16];
+        shifted_ri[1] = 1;
+
+        assert_eq!(localize_tampering(&grid, &ri, &shifted_ri), [BlockRegion { x: 64, y: 32, width: 16, height: 16 }]);
+    }
+
+    #[test]
+    fn edits_on_block_boundaries_are_localized() {
+        // An edit around (64, 64) flags the four main blocks it touches but a single shifted block
+        let grid = BlockGrid::new(128, 128, LAYOUT);
+        let mut ri = vec![0; 16];
+        for index in [5, 6, 9, 10] {
+            ri[index] = 1;
+        }
+        let mut shifted_ri = vec![0; 16];
+        shifted_ri[5] = 1;
+
+        let tampered = localize_tampering(&grid, &ri, &shifted_ri);
+        assert_eq!(tampered.len(), 4);
+        assert!(tampered.iter().all(|cell| cell.dimensions() == (16, 16)));
+        assert!(tampered.iter().all(|cell| (48..80).contains(&cell.x) && (48..80).contains(&cell.y)));
+    }
+
+    #[test]
+    fn edges_outside_the_shifted_grid_follow_the_main_grid() {
+        let mut ri = vec![0; 12];
+        ri[0] = 1;
+        let tampered = localize_tampering(&grid(), &ri, &[0; 6]);
+
+        // Only the three quarters of block 0 along the top and left edges remain
+        assert_eq!(
+            tampered,
+            [
+                BlockRegion { x: 0, y: 0, width: 16, height: 16 },
+                BlockRegion { x: 16, y: 0, width: 16, height: 16 },
+                BlockRegion { x: 0, y: 16, width: 16, height: 16 },
+            ]
+        );
+    }
+
+    #[test]
+    fn shifted_root_is_committed_in_the_header() {
+        let leaves: Vec<String> = ["a", "b", "c"].iter().map(|leaf| leaf.to_string()).collect();
+        let mut blockchain = Blockchain::new();
+        let shifted = ShiftedGrid { leaves: leaves.clone(), cids: Vec::new() };
+        blockchain.add_block("root".to_string(), Transaction { shifted: Some(shifted), ..test_registration(grid(), Vec::new(), Vec::new()) });
+
+        let header = &blockchain.chain.last().unwrap().header;
+        assert_eq!(header.shifted_root, build_tree(leaves, None).root_hex());
+
+        // Replacing the shifted leaves and their root changes the block hash
+        let mut forged = header.clone();
+        forged.shifted_root = build_tree(vec!["x".to_string()], None).root_hex();
+        assert_ne!(calculate_hash(&forged), calculate_hash(header));
+    }
+
+    #[test]
+    fn shifted_grid_has_its_own_image_id() {
+        assert_ne!(shifted_image_id("image", LAYOUT), crate::pyramid::level_image_id("image", LAYOUT));
+    }
+}
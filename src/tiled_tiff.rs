@@ -0,0 +1,373 @@
+// src/tiled_tiff.rs
+
+use crate::canonicalize::TIFF_ICC_PROFILE;
+use crate::image_to_chunks::{BlockGrid, BlockLayout, Padding};
+use image::{DynamicImage, ImageBuffer};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use thiserror::Error;
+use tiff::decoder::Decoder;
+use tiff::tags::Tag;
+use tiff::TiffError;
+
+#[derive(Debug, Error)]
+pub enum TiledTiffError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Tiff(#[from] TiffError),
+    #[error("the TIFF image is stored in strips, not tiles")]
+    NotTiled,
+    #[error("unsupported tiled TIFF: {0}")]
+    Unsupported(String),
+    #[error("tile {index} is corrupt: {reason}")]
+    CorruptTile { index: usize, reason: String },
+}
+
+// Compression schemes of the tiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    None,
+    Lzw,
+    Deflate,
+}
+
+// A tiled TIFF whose tiles are decoded one at a time, so that images far larger than the
+// memory can be registered. Only the tags are read when it is opened.
+#[derive(Debug)]
+pub struct TiledTiff {
+    file: File,
+    pub width: u32,
+    pub height: u32,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    big_endian: bool,
+    compression: Compression,
+    horizontal_predictor: bool,
+    bits_per_sample: u16,
+    samples_per_pixel: u16,
+    tile_offsets: Vec<u64>,
+    tile_byte_counts: Vec<u64>,
+}
+
+impl TiledTiff {
+    // Read the tags of the first image of a tiled TIFF. Tiles are decoded to the same pixels as
+    // `load_canonical_image`, so only upright TIFFs without an ICC profile are supported.
+    pub fn open(img_path: &str) -> Result<Self, TiledTiffError> {
+        let mut decoder = Decoder::new(BufReader::new(File::open(img_path)?))?;
+        let (width, height) = decoder.dimensions()?;
+        let (tile_width, tile_height) = match (decoder.find_tag_unsigned(Tag::TileWidth)?, decoder.find_tag_unsigned(Tag::TileLength)?) {
+            (Some(tile_width), Some(tile_height)) => (tile_width, tile_height),
+            _ => return Err(TiledTiffError::NotTiled),
+        };
+        if tile_width == 0 || tile_height == 0 {
+            return Err(TiledTiffError::Unsupported(format!("{}x{} tiles", tile_width, tile_height)));
+        }
+
+        let compression = match decoder.find_tag_unsigned::<u16>(Tag::Compression)?.unwrap_or(1) {
+            1 => Compression::None,
+            5 => Compression::Lzw,
+            8 | 32946 => Compression::Deflate,
+            other => return Err(TiledTiffError::Unsupported(format!("compression {}", other))),
+        };
+        let horizontal_predictor = match decoder.find_tag_unsigned::<u16>(Tag::Predictor)?.unwrap_or(1) {
+            1 => false,
+            2 => true,
+            other => return Err(TiledTiffError::Unsupported(format!("predictor {}", other))),
+        };
+
+        let samples_per_pixel = decoder.find_tag_unsigned::<u16>(Tag::SamplesPerPixel)?.unwrap_or(1);
+        let bits = decoder.find_tag_unsigned_vec::<u16>(Tag::BitsPerSample)?.unwrap_or_else(|| vec![1]);
+        let bits_per_sample = bits[0];
+        if !matches!(bits_per_sample, 8 | 16) || bits.iter().any(|&b| b != bits_per_sample) {
+            return Err(TiledTiffError::Unsupported(format!("{:?} bits per sample", bits)));
+        }
+        let photometric = decoder.find_tag_unsigned::<u16>(Tag::PhotometricInterpretation)?;
+        match (photometric, samples_per_pixel) {
+            (Some(1), 1 | 2) | (Some(2), 3 | 4) => {}
+            _ => return Err(TiledTiffError::Unsupported(format!("photometric interpretation {:?} with {} samples", photometric, samples_per_pixel))),
+        }
+        if decoder.find_tag_unsigned::<u16>(Tag::PlanarConfiguration)?.unwrap_or(1) != 1 {
+            return Err(TiledTiffError::Unsupported("planar configuration".to_string()));
+        }
+        if decoder.find_tag_unsigned::<u16>(Tag::SampleFormat)?.unwrap_or(1) != 1 {
+            return Err(TiledTiffError::Unsupported("non integer samples".to_string()));
+        }
+        if decoder.find_tag_unsigned::<u16>(Tag::Orientation)?.unwrap_or(1) != 1 {
+            return Err(TiledTiffError::Unsupported("orientation".to_string()));
+        }
+        if decoder.find_tag(Tag::Unknown(TIFF_ICC_PROFILE))?.is_some() {
+            return Err(TiledTiffError::Unsupported("ICC profile".to_string()));
+        }
+
+        let tile_offsets = decoder.find_tag_unsigned_vec(Tag::TileOffsets)?.ok_or(TiledTiffError::NotTiled)?;
+        let tile_byte_counts = decoder.find_tag_unsigned_vec(Tag::TileByteCounts)?.ok_or(TiledTiffError::NotTiled)?;
+
+        // The byte order is in the header of the file
+        let mut file = File::open(img_path)?;
+        let mut byte_order = [0u8; 2];
+        file.read_exact(&mut byte_order)?;
+
+        let tiff = TiledTiff {
+            file,
+            width,
+            height,
+            tile_width,
+            tile_height,
+            big_endian: &byte_order == b"MM",
+            compression,
+            horizontal_predictor,
+            bits_per_sample,
+            samples_per_pixel,
+            tile_offsets,
+            tile_byte_counts,
+        };
+        let tile_count = tiff.tile_grid(Padding::Zero).block_count();
+        if tiff.tile_offsets.len() < tile_count || tiff.tile_byte_counts.len() < tile_count {
+            return Err(TiledTiffError::Unsupported(format!("{} tile offsets for {} tiles", tiff.tile_offsets.len(), tile_count)));
+        }
+
+        // Tiles are read into buffers of their byte count, which must not run past the file
+        let file_len = tiff.file.metadata()?.len();
+        for (index, (&offset, &byte_count)) in tiff.tile_offsets.iter().zip(&tiff.tile_byte_counts).take(tile_count).enumerate() {
+            if offset.checked_add(byte_count).is_none_or(|end| end > file_len) {
+                let reason = format!("{} bytes at offset {} in a file of {} bytes", byte_count, offset, file_len);
+                return Err(TiledTiffError::CorruptTile { index, reason });
+            }
+        }
+        Ok(tiff)
+    }
+
+    // Block grid following the tiles, so that each block is exactly one tile
+    pub fn tile_grid(&self, padding: Padding) -> BlockGrid {
+        BlockGrid::new(self.width, self.height, BlockLayout { block_width: self.tile_width, block_height: self.tile_height, padding })
+    }
+
+    // Decode tile `index`, in reading order, clipped to the image and converted like
+    // `load_canonical_image` to 8-bit or 16-bit RGBA
+    pub fn read_tile(&mut self, index: usize) -> Result<DynamicImage, TiledTiffError> {
+        let corrupt = |reason: String| TiledTiffError::CorruptTile { index, reason };
+
+        let mut data = vec![0u8; self.tile_byte_counts[index] as usize];
+        self.file.seek(SeekFrom::Start(self.tile_offsets[index]))?;
+        self.file.read_exact(&mut data)?;
+        let data = match self.compression {
+            Compression::None => data,
+            Compression::Lzw => weezl::decode::Decoder::with_tiff_size_switch(weezl::BitOrder::Msb, 8)
+                .decode(&data)
+                .map_err(|e| corrupt(e.to_string()))?,
+            Compression::Deflate => miniz_oxide::inflate::decompress_to_vec_zlib(&data).map_err(|e| corrupt(format!("{:?}", e)))?,
+        };
+
+        // Tiles are stored whole, including the part beyond the right and bottom edges
+        let channels = self.samples_per_pixel as usize;
+        let row_len = self.tile_width as usize * channels;
+        let sample_count = row_len * self.tile_height as usize;
+        let (x, y) = self.tile_grid(Padding::Zero).block_origin(index as u32);
+        let (width, height) = (self.tile_width.min(self.width - x), self.tile_height.min(self.height - y));
+
+        let tile = if self.bits_per_sample == 8 {
+            let mut samples = data;
+            if samples.len() < sample_count {
+                return Err(corrupt(format!("{} bytes for {} samples", samples.len(), sample_count)));
+            }
+            samples.truncate(sample_count);
+            if self.horizontal_predictor {
+                undo_horizontal_predictor(&mut samples, row_len, channels, u8::wrapping_add);
+            }
+            self.samples_to_image(clip_samples(&samples, row_len, width as usize * channels, height as usize), width, height)
+        } else {
+            if data.len() < sample_count * 2 {
+                return Err(corrupt(format!("{} bytes for {} samples", data.len(), sample_count)));
+            }
+            let mut samples: Vec<u16> = data[..sample_count * 2]
+                .chunks_exact(2)
+                .map(|bytes| if self.big_endian { u16::from_be_bytes([bytes[0], bytes[1]]) } else { u16::from_le_bytes([bytes[0], bytes[1]]) })
+                .collect();
+            if self.horizontal_predictor {
+                undo_horizontal_predictor(&mut samples, row_len, channels, u16::wrapping_add);
+            }
+            self.samples_to_image(clip_samples(&samples, row_len, width as usize * channels, height as usize), width, height)
+        };
+        tile.ok_or_else(|| corrupt("sample count does not match the tile".to_string()))
+    }
+
+    fn samples_to_image<S: TileSample>(&self, samples: Vec<S>, width: u32, height: u32) -> Option<DynamicImage> {
+        S::to_image(samples, self.samples_per_pixel, width, height)
+    }
+}
+
+// Channel depths tiles are decoded to
+trait TileSample: Sized {
+    fn to_image(samples: Vec<Self>, channels: u16, width: u32, height: u32) -> Option<DynamicImage>;
+}
+
+impl TileSample for u8 {
+    fn to_image(samples: Vec<u8>, channels: u16, width: u32, height: u32) -> Option<DynamicImage> {
+        let img = match channels {
+            1 => DynamicImage::ImageLuma8(ImageBuffer::from_raw(width, height, samples)?),
+            2 => DynamicImage::ImageLumaA8(ImageBuffer::from_raw(width, height, samples)?),
+            3 => DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, samples)?),
+            _ => DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, samples)?),
+        };
+        Some(DynamicImage::ImageRgba8(img.to_rgba8()))
+    }
+}
+
+impl TileSample for u16 {
+    fn to_image(samples: Vec<u16>, channels: u16, width: u32, height: u32) -> Option<DynamicImage> {
+        let img = match channels {
+            1 => DynamicImage::ImageLuma16(ImageBuffer::from_raw(width, height, samples)?),
+            2 => DynamicImage::ImageLumaA16(ImageBuffer::from_raw(width, height, samples)?),
+            3 => DynamicImage::ImageRgb16(ImageBuffer::from_raw(width, height, samples)?),
+            _ => DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, samples)?),
+        };
+        Some(DynamicImage::ImageRgba16(img.to_rgba16()))
+    }
+}
+
+// Each sample is stored as the difference with the same channel of the previous pixel of its row
+fn undo_horizontal_predictor<S: Copy>(samples: &mut [S], row_len: usize, channels: usize, add: fn(S, S) -> S) {
+    for row in samples.chunks_exact_mut(row_len) {
+        for i in channels..row.len() {
+            row[i] = add(row[i], row[i - channels]);
+        }
+    }
+}
+
+// Keep the first `height` rows of `width` samples of a tile
+fn clip_samples<S: Copy>(samples: &[S], row_len: usize, width: usize, height: usize) -> Vec<S> {
+    samples.chunks_exact(row_len).take(height).flat_map(|row| &row[..width]).copied().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, Rgb, RgbImage};
+    use std::io::Write;
+
+    // Minimal little-endian tiled TIFF writer: 8-bit RGB tiles, uncompressed or deflated
+    fn write_tiled_tiff(path: &std::path::Path, image: &RgbImage, tile: u32, deflate: bool) {
+        let grid = BlockGrid::new(image.width(), image.height(), BlockLayout { block_width: tile, block_height: tile, padding: Padding::Zero });
+        let tiles: Vec<Vec<u8>> = grid
+            .regions()
+            .iter()
+            .map(|region| {
+                let raw: Vec<u8> = (region.y..region.y + tile)
+                    .flat_map(|y| (region.x..region.x + tile).map(move |x| (x, y)))
+                    .flat_map(|(x, y)| if x < image.width() && y < image.height() { image.get_pixel(x, y).0 } else { [0; 3] })
+                    .collect();
+                if deflate { miniz_oxide::deflate::compress_to_vec_zlib(&raw, 6) } else { raw }
+            })
+            .collect();
+
+        // Header, tile data, then the out-of-line tag values and the directory
+        let mut out = b"II*\0\0\0\0\0".to_vec();
+        let mut offsets = Vec::new();
+        for data in &tiles {
+            offsets.push(out.len() as u32);
+            out.extend_from_slice(data);
+        }
+        let mut values = |data: Vec<u8>| {
+            let offset = out.len() as u32;
+            out.extend_from_slice(&data);
+            offset
+        };
+        let bits = values([8u16, 8, 8].iter().flat_map(|b| b.to_le_bytes()).collect());
+        let offsets = values(offsets.iter().flat_map(|o| o.to_le_bytes()).collect());
+        let counts = values(tiles.iter().flat_map(|t| (t.len() as u32).to_le_bytes()).collect());
+
+        // (tag, type, count, value or offset), in increasing tag order
+        let entries: [(u16, u16, u32, u32); 10] = [
+            (256, 4, 1, image.width()),
+            (257, 4, 1, image.height()),
+            (258, 3, 3, bits),
+            (259, 3, 1, if deflate { 8 } else { 1 }),
+            (262, 3, 1, 2),
+            (277, 3, 1, 3),
+            (322, 4, 1, tile),
+            (323, 4, 1, tile),
+            (324, 4, tiles.len() as u32, offsets),
+            (325, 4, tiles.len() as u32, counts),
+        ];
+        let directory = out.len() as u32;
+        out[4..8].copy_from_slice(&directory.to_le_bytes());
+        out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        for (tag, kind, count, value) in entries {
+            out.extend_from_slice(&tag.to_le_bytes());
+            out.extend_from_slice(&kind.to_le_bytes());
+            out.extend_from_slice(&count.to_le_bytes());
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        out.extend_from_slice(&0u32.to_le_bytes());
+
+        File::create(path).unwrap().write_all(&out).unwrap();
+    }
+
+    // 40x24 image whose pixels encode their position
+    fn test_image() -> RgbImage {
+        RgbImage::from_fn(40, 24, |x, y| Rgb([x as u8, y as u8, (x * 7 + y * 3) as u8]))
+    }
+
+    fn check_tiles(name: &str, deflate: bool) {
+        let path = std::env::temp_dir().join(format!("tiled_tiff_{}_{}.tif", name, std::process::id()));
+        let image = test_image();
+        write_tiled_tiff(&path, &image, 16, deflate);
+
+        let mut tiff = TiledTiff::open(path.to_str().unwrap()).unwrap();
+        let grid = tiff.tile_grid(Padding::Zero);
+        assert_eq!((grid.columns(), grid.rows()), (3, 2));
+
+        let canonical = DynamicImage::ImageRgba8(DynamicImage::ImageRgb8(image).to_rgba8());
+        for (index, region) in grid.regions().into_iter().enumerate() {
+            let tile = tiff.read_tile(index).unwrap();
+            let (width, height) = tile.dimensions();
+            assert_eq!((width, height), (region.width.min(40 - region.x), region.height.min(24 - region.y)));
+            assert_eq!(tile.to_rgba8(), canonical.crop_imm(region.x, region.y, width, height).to_rgba8(), "tile {}", index);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn uncompressed_tiles_are_clipped_to_the_image() {
+        check_tiles("raw", false);
+    }
+
+    #[test]
+    fn deflated_tiles_are_decoded() {
+        check_tiles("deflate", true);
+    }
+
+    #[test]
+    fn predictor_differences_are_summed_along_rows() {
+        let mut samples = vec![10u8, 20, 1, 2, 255, 1, 5, 5, 1, 1, 1, 1];
+        undo_horizontal_predictor(&mut samples, 6, 2, u8::wrapping_add);
+        assert_eq!(samples, [10, 20, 11, 22, 10, 23, 5, 5, 6, 6, 7, 7]);
+    }
+
+    #[test]
+    fn tiles_past_the_end_of_the_file_are_rejected() {
+        let path = std::env::temp_dir().join(format!("tiled_tiff_truncated_{}.tif", std::process::id()));
+        write_tiled_tiff(&path, &test_image(), 16, false);
+        let mut data = std::fs::read(&path).unwrap();
+
+        // Byte count of the second tile, from the TileByteCounts entry of the directory
+        let directory = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+        let entry = directory + 2 + 9 * 12;
+        let counts = u32::from_le_bytes(data[entry + 8..entry + 12].try_into().unwrap()) as usize;
+        data[counts + 4..counts + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&path, &data).unwrap();
+
+        let opened = TiledTiff::open(path.to_str().unwrap());
+        std::fs::remove_file(path).unwrap();
+        assert!(matches!(opened, Err(TiledTiffError::CorruptTile { index: 1, .. })), "{:?}", opened);
+    }
+
+    #[test]
+    fn images_in_strips_are_not_tiled() {
+        let path = std::env::temp_dir().join(format!("tiled_tiff_strips_{}.tif", std::process::id()));
+        DynamicImage::ImageRgb8(test_image()).save(&path).unwrap();
+        assert!(matches!(TiledTiff::open(path.to_str().unwrap()), Err(TiledTiffError::NotTiled)));
+        std::fs::remove_file(path).unwrap();
+    }
+}